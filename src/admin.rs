@@ -0,0 +1,131 @@
+use crate::config;
+use crate::control::{Control, InFlightStatus, RecentOutcomes};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::{get, post, put};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::FileTypeExt;
+use std::sync::Arc;
+use tokio::net::{TcpListener, UnixListener};
+
+//
+// Data structures
+//
+
+/// The state of the runner, as reported by the admin API.
+#[derive(Serialize, Deserialize)]
+pub struct Status {
+    pub paused: bool,
+    pub draining: bool,
+    pub max_tasks: usize,
+    pub in_flight: Vec<InFlightStatus>,
+    pub recent: RecentOutcomes,
+}
+
+/// The listener for the admin API.
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+#[derive(Deserialize)]
+struct MaxTasksRequest {
+    max_tasks: usize,
+}
+
+
+//
+// Functions
+//
+
+/// Bind the listener for the admin API, so that a failure can be reported at startup.
+pub async fn bind(config: &config::Admin) -> io::Result<Listener> {
+    if let Some(socket_path) = &config.socket_path {
+        //Remove a socket left behind by a previous run, but never any other kind of file
+        match fs::symlink_metadata(socket_path) {
+            Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(socket_path)?,
+            Ok(_) => {
+                let message = format!("{} already exists and is not a socket", socket_path);
+                return Err(io::Error::new(ErrorKind::AlreadyExists, message));
+            },
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            Err(_) => {},
+        }
+        let listener = UnixListener::bind(socket_path)?;
+        log::info!("Serving admin API on unix socket {}", socket_path);
+        Ok(Listener::Unix(listener))
+    } else {
+        let listener = TcpListener::bind((config.address.as_str(), config.port.unwrap_or_default())).await?;
+        log::info!("Serving admin API on http://{}", listener.local_addr()?);
+        Ok(Listener::Tcp(listener))
+    }
+}
+
+/// Serve the admin API, until the process exits.
+pub async fn serve(listener: Listener, control: Arc<Control>) -> io::Result<()> {
+    let app = Router::new()
+        .route("/status", get(get_status))
+        .route("/pause", post(post_pause))
+        .route("/resume", post(post_resume))
+        .route("/drain", post(post_drain))
+        .route("/max_tasks", put(put_max_tasks))
+        .with_state(control);
+
+    match listener {
+        Listener::Tcp(listener) => axum::serve(listener, app).await,
+        Listener::Unix(listener) => axum::serve(listener, app).await,
+    }
+}
+
+/// Report whether polling is paused, the concurrency limit, the tasks currently in flight, and
+/// the number of tasks which recently succeeded and failed.
+async fn get_status(State(control): State<Arc<Control>>) -> Json<Status> {
+    Json(status(&control))
+}
+
+/// Stop polling for new tasks. Tasks already in flight are allowed to finish.
+async fn post_pause(State(control): State<Arc<Control>>) -> Json<Status> {
+    log::info!("Pausing polling, at the request of the admin API");
+    control.set_paused(true);
+    Json(status(&control))
+}
+
+async fn post_resume(State(control): State<Arc<Control>>) -> Json<Status> {
+    log::info!("Resuming polling, at the request of the admin API");
+    control.set_paused(false);
+    Json(status(&control))
+}
+
+/// Stop polling for new tasks, and exit once the tasks in flight have finished.
+async fn post_drain(State(control): State<Arc<Control>>) -> Json<Status> {
+    log::info!("Draining, at the request of the admin API");
+    control.request_drain();
+    Json(status(&control))
+}
+
+/// Change the number of tasks which may be processed in parallel.
+async fn put_max_tasks(
+    State(control): State<Arc<Control>>,
+    Json(request): Json<MaxTasksRequest>,
+) -> Result<Json<Status>, (StatusCode, String)> {
+    if request.max_tasks == 0 {
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, "max_tasks must be at least 1".to_string()));
+    }
+    log::info!("Setting max_tasks to {}, at the request of the admin API", request.max_tasks);
+    control.set_max_tasks(request.max_tasks);
+    Ok(Json(status(&control)))
+}
+
+fn status(control: &Control) -> Status {
+    let settings = control.settings();
+    Status {
+        paused: settings.paused,
+        draining: control.is_draining(),
+        max_tasks: settings.max_tasks,
+        in_flight: control.in_flight(),
+        recent: control.recent_outcomes(),
+    }
+}
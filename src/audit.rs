@@ -0,0 +1,309 @@
+use crate::config::{self, AuditFormat};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OpenFlags};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::result;
+use std::sync::Mutex;
+use thiserror::Error;
+
+/// The value which replaces redacted CGI environment variables.
+const REDACTED: &str = "[REDACTED]";
+
+//
+// Data structures
+//
+
+/// A durable record of every task processed, written to a rotating JSONL file or an SQLite
+/// database.
+pub struct AuditLog {
+    sink: Sink,
+    redact: Vec<String>,
+    max_output_bytes: usize,
+}
+
+enum Sink {
+    Jsonl {
+        path: PathBuf,
+        max_bytes: u64,
+        max_files: u32,
+        file: Mutex<Option<File>>,
+    },
+    Sqlite(Mutex<Connection>),
+}
+
+/// The audit record of a single attempt at processing a task.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Record {
+    pub message_id: String,
+    pub queue: String,
+    pub received_at: DateTime<Utc>,
+    pub completed_at: DateTime<Utc>,
+    pub attempt: u64,
+    /// The CGI environment variables set by the field mappings
+    pub env: BTreeMap<String, String>,
+    pub status: Option<u16>,
+    pub stdout: String,
+    pub stderr: String,
+    pub succeeded: bool,
+    pub error: Option<String>,
+    /// Whether the item was successfully removed from the queue
+    pub acknowledged: bool,
+}
+
+/// Criteria for searching the audit log.
+pub struct Query {
+    pub message_id: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub limit: Option<usize>,
+}
+
+
+//
+// Functions
+//
+
+impl AuditLog {
+    /// Open the log for writing, creating the SQLite table if necessary. A JSONL file is created
+    /// when the first record is written.
+    pub fn open(config: &config::Audit) -> Result<Self> {
+        let sink = match config.format {
+            AuditFormat::Jsonl => Sink::jsonl(config),
+            AuditFormat::Sqlite => {
+                let connection = Connection::open(&config.path)?;
+                connection.execute_batch(
+                    "CREATE TABLE IF NOT EXISTS audit (
+                        message_id TEXT NOT NULL,
+                        queue TEXT NOT NULL,
+                        received_at TEXT NOT NULL,
+                        completed_at TEXT NOT NULL,
+                        attempt INTEGER NOT NULL,
+                        env TEXT NOT NULL,
+                        status INTEGER,
+                        stdout TEXT NOT NULL,
+                        stderr TEXT NOT NULL,
+                        succeeded INTEGER NOT NULL,
+                        error TEXT,
+                        acknowledged INTEGER NOT NULL
+                    );
+                    CREATE INDEX IF NOT EXISTS audit_message_id ON audit (message_id);
+                    CREATE INDEX IF NOT EXISTS audit_received_at ON audit (received_at);"
+                )?;
+                Sink::Sqlite(Mutex::new(connection))
+            },
+        };
+        Ok(AuditLog::new(config, sink))
+    }
+
+    /// Open the log for searching only, without creating or modifying anything.
+    pub fn open_read_only(config: &config::Audit) -> Result<Self> {
+        let sink = match config.format {
+            AuditFormat::Jsonl => Sink::jsonl(config),
+            AuditFormat::Sqlite => {
+                let connection = Connection::open_with_flags(&config.path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+                Sink::Sqlite(Mutex::new(connection))
+            },
+        };
+        Ok(AuditLog::new(config, sink))
+    }
+
+    fn new(config: &config::Audit, sink: Sink) -> Self {
+        AuditLog {
+            sink,
+            redact: config.redact.clone(),
+            max_output_bytes: config.max_output_bytes,
+        }
+    }
+
+    /// Add a record to the log, applying the configured redaction and truncation.
+    pub fn write(&self, mut record: Record) -> Result<()> {
+        for (key, val) in record.env.iter_mut() {
+            if self.redact.iter().any(|redacted| redacted.eq_ignore_ascii_case(key)) {
+                *val = REDACTED.to_string();
+            }
+        }
+        truncate(&mut record.stdout, self.max_output_bytes);
+        truncate(&mut record.stderr, self.max_output_bytes);
+
+        match &self.sink {
+            Sink::Jsonl { path, max_bytes, max_files, file } => {
+                let mut file = file.lock().unwrap();
+                if fs::metadata(path).is_ok_and(|metadata| metadata.len() >= *max_bytes) {
+                    *file = None;
+                    rotate(path, *max_files)?;
+                }
+                if file.is_none() {
+                    *file = Some(OpenOptions::new().create(true).append(true).open(path)?);
+                }
+                let mut line = serde_json::to_vec(&record)?;
+                line.push(b'\n');
+                file.as_mut().unwrap().write_all(&line)?;
+            },
+            Sink::Sqlite(connection) => {
+                connection.lock().unwrap().execute(
+                    "INSERT INTO audit (message_id, queue, received_at, completed_at, attempt, env, status, stdout, stderr, succeeded, error, acknowledged)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                    params![
+                        record.message_id,
+                        record.queue,
+                        record.received_at,
+                        record.completed_at,
+                        record.attempt,
+                        serde_json::to_string(&record.env)?,
+                        record.status,
+                        record.stdout,
+                        record.stderr,
+                        record.succeeded,
+                        record.error,
+                        record.acknowledged,
+                    ],
+                )?;
+            },
+        }
+        Ok(())
+    }
+
+    /// Search the log for records matching the query, in the order they were received.
+    pub fn search(&self, query: &Query) -> Result<Vec<Record>> {
+        let mut records = match &self.sink {
+            Sink::Jsonl { path, max_files, .. } => {
+                let mut records = Vec::new();
+                //Read the oldest rotated file first
+                for index in (0..=*max_files).rev() {
+                    let file = match File::open(rotated_path(path, index)) {
+                        Ok(file) => file,
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                        Err(e) => return Err(e.into()),
+                    };
+                    for line in BufReader::new(file).lines() {
+                        let record: Record = serde_json::from_str(&line?)?;
+                        if query.matches(&record) {
+                            records.push(record);
+                        }
+                    }
+                }
+                records
+            },
+            Sink::Sqlite(connection) => {
+                let connection = connection.lock().unwrap();
+                let mut statement = connection.prepare(
+                    "SELECT message_id, queue, received_at, completed_at, attempt, env, status, stdout, stderr, succeeded, error, acknowledged
+                    FROM audit
+                    WHERE (?1 IS NULL OR message_id = ?1) AND (?2 IS NULL OR received_at >= ?2) AND (?3 IS NULL OR received_at < ?3)
+                    ORDER BY received_at"
+                )?;
+                let rows = statement.query_map(
+                    params![query.message_id, query.since, query.until],
+                    |row| {
+                        let record = Record {
+                            message_id: row.get(0)?,
+                            queue: row.get(1)?,
+                            received_at: row.get(2)?,
+                            completed_at: row.get(3)?,
+                            attempt: row.get(4)?,
+                            env: BTreeMap::new(),
+                            status: row.get(6)?,
+                            stdout: row.get(7)?,
+                            stderr: row.get(8)?,
+                            succeeded: row.get(9)?,
+                            error: row.get(10)?,
+                            acknowledged: row.get(11)?,
+                        };
+                        Ok((record, row.get::<_, String>(5)?))
+                    },
+                )?;
+                let mut records = Vec::new();
+                for row in rows {
+                    let (mut record, env) = row?;
+                    record.env = serde_json::from_str(&env)?;
+                    records.push(record);
+                }
+                records
+            },
+        };
+        if let Some(limit) = query.limit {
+            records.truncate(limit);
+        }
+        Ok(records)
+    }
+}
+
+impl Sink {
+    fn jsonl(config: &config::Audit) -> Self {
+        Sink::Jsonl {
+            path: PathBuf::from(&config.path),
+            max_bytes: config.max_bytes,
+            max_files: config.max_files,
+            file: Mutex::new(None),
+        }
+    }
+}
+
+impl Query {
+    fn matches(&self, record: &Record) -> bool {
+        self.message_id.as_ref().is_none_or(|id| *id == record.message_id)
+            && self.since.is_none_or(|since| record.received_at >= since)
+            && self.until.is_none_or(|until| record.received_at < until)
+    }
+}
+
+/// Shift each rotated file along by one (`audit.jsonl` becomes `audit.jsonl.1`, and so on),
+/// discarding the oldest.
+fn rotate(path: &Path, max_files: u32) -> io::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    if max_files == 0 {
+        return fs::remove_file(path);
+    }
+    for index in (1..max_files).rev() {
+        let from = rotated_path(path, index);
+        if from.exists() {
+            fs::rename(from, rotated_path(path, index + 1))?;
+        }
+    }
+    fs::rename(path, rotated_path(path, 1))
+}
+
+fn rotated_path(path: &Path, index: u32) -> PathBuf {
+    if index == 0 {
+        return path.to_path_buf();
+    }
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", index));
+    rotated.into()
+}
+
+/// Shorten a string to at most `max_bytes`, without splitting a UTF-8 character.
+fn truncate(str: &mut String, max_bytes: usize) {
+    if str.len() <= max_bytes {
+        return;
+    }
+    let mut end = max_bytes;
+    while !str.is_char_boundary(end) {
+        end -= 1;
+    }
+    str.truncate(end);
+}
+
+//
+// Error handling
+//
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+pub type Result<T> = result::Result<T, Error>;
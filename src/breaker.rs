@@ -0,0 +1,252 @@
+use crate::config;
+use crate::metrics;
+use crate::pool::Pool;
+use anyhow::anyhow;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::time::{sleep_until, timeout};
+
+/// How long a probe connection may take before the probe is considered failed.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+//
+// Data structures
+//
+
+/// Stops tasks being received and dispatched while the FastCGI server isn't accepting
+/// connections, so that an outage doesn't use up the receive attempts of the items on the queue.
+pub struct CircuitBreaker {
+    config: Option<config::CircuitBreaker>,
+    state: Mutex<State>,
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    /// Tasks are dispatched as normal, and consecutive connection failures are counted.
+    Closed { failures: u32 },
+    /// Tasks aren't dispatched. The server will be probed once `probe_at` is reached.
+    Open { probe_at: Instant, cooldown: Duration },
+    /// Tasks aren't dispatched, and the server is being probed.
+    HalfOpen { cooldown: Duration },
+}
+
+
+//
+// Functions
+//
+
+impl CircuitBreaker {
+    /// Create a circuit breaker, which never opens if it isn't configured.
+    pub fn new(config: Option<config::CircuitBreaker>) -> Self {
+        metrics::CIRCUIT_BREAKER_OPEN.set(0);
+        CircuitBreaker { config, state: Mutex::new(State::Closed { failures: 0 }) }
+    }
+
+    /// Whether tasks may be dispatched.
+    pub fn is_closed(&self) -> bool {
+        matches!(*self.state.lock().unwrap(), State::Closed { .. })
+    }
+
+    /// Record that a connection to the FastCGI server was made.
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let State::Closed { failures } = &mut *state {
+            *failures = 0;
+        }
+    }
+
+    /// Record that a connection to the FastCGI server couldn't be made, opening the circuit
+    /// breaker if this is one failure too many.
+    pub fn record_failure(&self) {
+        self.record_failure_at(Instant::now());
+    }
+
+    fn record_failure_at(&self, now: Instant) {
+        let Some(config) = &self.config else {
+            return;
+        };
+        let mut state = self.state.lock().unwrap();
+        if let State::Closed { failures } = &mut *state {
+            *failures += 1;
+            if *failures >= config.failure_threshold {
+                let cooldown = Duration::from_secs(config.cooldown);
+                log::error!(
+                    "Unable to connect to FastCGI {} times in a row; not receiving tasks until it is accepting connections (next check in {}s)",
+                    failures, cooldown.as_secs()
+                );
+                *state = State::Open { probe_at: now + cooldown, cooldown };
+                metrics::CIRCUIT_BREAKER_OPEN.set(1);
+            }
+        }
+    }
+
+    /// Wait until the circuit breaker is closed. While it is open, the FastCGI server is probed
+    /// periodically, and the circuit breaker closes once a connection can be made.
+    pub async fn wait_until_closed(&self, pool: &Pool) {
+        while let Some(probe_at) = self.next_probe(Instant::now()) {
+            sleep_until(probe_at.into()).await;
+            self.start_probe();
+            let result = match timeout(PROBE_TIMEOUT, pool.check_connection()).await {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(e)) => Err(format!("{:#}", anyhow!(e))),
+                Err(_) => Err("timed out connecting to FastCGI".to_string()),
+            };
+            self.finish_probe(result, Instant::now());
+        }
+    }
+
+    /// When the server should next be probed, or `None` if the circuit breaker is closed.
+    fn next_probe(&self, now: Instant) -> Option<Instant> {
+        match *self.state.lock().unwrap() {
+            State::Closed { .. } => None,
+            State::Open { probe_at, .. } => Some(probe_at),
+            //A previous probe was interrupted, so try again straight away
+            State::HalfOpen { .. } => Some(now),
+        }
+    }
+
+    fn start_probe(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let State::Open { cooldown, .. } = *state {
+            *state = State::HalfOpen { cooldown };
+        }
+    }
+
+    /// Close the circuit breaker if the probe succeeded. Otherwise, schedule another probe, after
+    /// twice the previous cooldown (up to the maximum).
+    fn finish_probe(&self, result: Result<(), String>, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        let State::HalfOpen { cooldown } = *state else {
+            return;
+        };
+        match result {
+            Ok(()) => {
+                log::info!("FastCGI is accepting connections again; resuming");
+                *state = State::Closed { failures: 0 };
+                metrics::CIRCUIT_BREAKER_OPEN.set(0);
+            },
+            Err(error) => {
+                let max_cooldown = self.config.as_ref()
+                    .map_or(cooldown, |config| Duration::from_secs(config.max_cooldown));
+                let cooldown = (cooldown * 2).min(max_cooldown);
+                log::warn!("FastCGI is still unavailable (next check in {}s): {}", cooldown.as_secs(), error);
+                *state = State::Open { probe_at: now + cooldown, cooldown };
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn breaker(failure_threshold: u32, cooldown: u64, max_cooldown: u64) -> CircuitBreaker {
+        CircuitBreaker::new(Some(config::CircuitBreaker { failure_threshold, cooldown, max_cooldown }))
+    }
+
+    fn state(breaker: &CircuitBreaker) -> State {
+        *breaker.state.lock().unwrap()
+    }
+
+    /// Probe the server, as `wait_until_closed` does once the probe is due, returning the cooldown
+    /// before the next probe.
+    fn probe(breaker: &CircuitBreaker, result: Result<(), String>, now: Instant) -> Option<Duration> {
+        breaker.start_probe();
+        breaker.finish_probe(result, now);
+        match state(breaker) {
+            State::Open { probe_at, cooldown } => {
+                assert_eq!(probe_at, now + cooldown);
+                Some(cooldown)
+            },
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn opens_after_threshold_of_consecutive_failures() {
+        let breaker = breaker(3, 5, 60);
+        let now = Instant::now();
+        breaker.record_failure_at(now);
+        breaker.record_failure_at(now);
+        assert!(breaker.is_closed());
+        breaker.record_failure_at(now);
+        assert!(!breaker.is_closed());
+        assert!(matches!(state(&breaker), State::Open { probe_at, cooldown }
+            if probe_at == now + Duration::from_secs(5) && cooldown == Duration::from_secs(5)));
+        assert_eq!(breaker.next_probe(now), Some(now + Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn success_resets_failure_count() {
+        let breaker = breaker(2, 5, 60);
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        assert!(breaker.is_closed());
+        breaker.record_failure();
+        assert!(!breaker.is_closed());
+    }
+
+    #[test]
+    fn never_opens_if_not_configured() {
+        let breaker = CircuitBreaker::new(None);
+        for _ in 0..100 {
+            breaker.record_failure();
+        }
+        assert!(breaker.is_closed());
+        assert_eq!(breaker.next_probe(Instant::now()), None);
+    }
+
+    #[test]
+    fn failed_probes_double_cooldown_up_to_maximum() {
+        let breaker = breaker(1, 5, 30);
+        let now = Instant::now();
+        breaker.record_failure_at(now);
+        let cooldowns: Vec<u64> = (0..4)
+            .map(|_| probe(&breaker, Err("refused".to_string()), now).unwrap().as_secs())
+            .collect();
+        assert_eq!(cooldowns, [10, 20, 30, 30]);
+    }
+
+    #[test]
+    fn successful_probe_closes_and_resets() {
+        let breaker = breaker(2, 5, 60);
+        let now = Instant::now();
+        breaker.record_failure_at(now);
+        breaker.record_failure_at(now);
+        assert_eq!(probe(&breaker, Err("refused".to_string()), now), Some(Duration::from_secs(10)));
+        assert_eq!(probe(&breaker, Ok(()), now), None);
+        assert!(breaker.is_closed());
+
+        //The failure count and cooldown start again from scratch
+        breaker.record_failure_at(now);
+        assert!(breaker.is_closed());
+        breaker.record_failure_at(now);
+        assert!(matches!(state(&breaker), State::Open { cooldown, .. } if cooldown == Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn interrupted_probe_is_retried_straight_away() {
+        let breaker = breaker(1, 5, 60);
+        let now = Instant::now();
+        breaker.record_failure_at(now);
+        breaker.start_probe();
+        assert!(matches!(state(&breaker), State::HalfOpen { .. }));
+        let later = now + Duration::from_secs(1);
+        assert_eq!(breaker.next_probe(later), Some(later));
+    }
+
+    #[tokio::test]
+    async fn wait_until_closed_probes_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let pool = Pool::new("127.0.0.1".to_string(), port, String::new());
+        let breaker = breaker(1, 0, 0);
+        breaker.record_failure();
+        assert!(!breaker.is_closed());
+        timeout(Duration::from_secs(5), breaker.wait_until_closed(&pool)).await.unwrap();
+        assert!(breaker.is_closed());
+    }
+}
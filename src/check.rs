@@ -0,0 +1,143 @@
+use crate::cli::CheckArgs;
+use crate::config::Source;
+use crate::pool::{self, HttpResponse, Pool};
+use crate::queue::Queue;
+use anyhow::anyhow;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// How long each connectivity check may take before it is considered failed.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+//
+// Data structures
+//
+
+/// The result of a single preflight check.
+pub struct CheckResult {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    pub detail: String,
+}
+
+#[derive(PartialEq)]
+pub enum Outcome {
+    Pass,
+    Fail,
+    Skip,
+}
+
+
+//
+// Functions
+//
+
+/// Check that the configuration file is valid, and that the queue and FastCGI server configured
+/// in it are reachable.
+pub async fn run(source: &Source, args: &CheckArgs) -> Vec<CheckResult> {
+    let mut results = Vec::new();
+
+    let config = match source.load() {
+        Ok(config) => {
+            results.push(CheckResult::pass("config", format!("configuration from {} is valid", source)));
+            config
+        },
+        Err(e) => {
+            results.push(CheckResult::fail("config", format!("{:#}", anyhow!(e))));
+            for name in ["sqs", "fastcgi", "ping"] {
+                results.push(CheckResult::skip(name, "the configuration is invalid"));
+            }
+            return results;
+        },
+    };
+
+    let queue = Queue::from_config(&config.queue).await;
+    results.push(match with_timeout(queue.check_access()).await {
+        Ok(()) => CheckResult::pass("sqs", format!("able to access queue {}", queue.name())),
+        Err(e) => CheckResult::fail("sqs", e),
+    });
+
+    let pool = Pool::new(
+        config.fastcgi.address.clone(),
+        config.fastcgi.port,
+        config.fastcgi.script_path.clone(),
+    );
+    let connected = match with_timeout(pool.check_connection()).await {
+        Ok(()) => {
+            let address = format!("{}:{}", config.fastcgi.address, config.fastcgi.port);
+            results.push(CheckResult::pass("fastcgi", format!("able to connect to {}", address)));
+            true
+        },
+        Err(e) => {
+            results.push(CheckResult::fail("fastcgi", e));
+            false
+        },
+    };
+
+    results.push(match &args.ping_script {
+        None => CheckResult::skip("ping", "no --ping-script given"),
+        Some(_) if !connected => CheckResult::skip("ping", "unable to connect to FastCGI"),
+        Some(ping_script) => ping(&pool, &config.fastcgi.cgi_environment, ping_script).await,
+    });
+
+    results
+}
+
+/// Run a script which should respond with a successful status code.
+async fn ping(pool: &Pool, cgi_environment: &HashMap<String, String>, ping_script: &str) -> CheckResult {
+    let env = HashMap::from([("SCRIPT_FILENAME".to_string(), ping_script.to_string())]);
+    let response = with_timeout(async {
+        let response: HttpResponse = pool.dispatch(&[], cgi_environment, env).await?.try_into()?;
+        Ok::<_, pool::Error>(response)
+    }).await;
+    match response {
+        Ok(response) if response.status().is_success() => {
+            CheckResult::pass("ping", format!("{} returned status code {}", ping_script, response.status()))
+        },
+        Ok(response) => {
+            CheckResult::fail("ping", format!("{} returned status code {}", ping_script, response.status()))
+        },
+        Err(e) => CheckResult::fail("ping", e),
+    }
+}
+
+/// Run a check, failing it if it takes too long.
+async fn with_timeout<T, E>(check: impl Future<Output = Result<T, E>>) -> Result<T, String>
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    match timeout(CHECK_TIMEOUT, check).await {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(e)) => Err(format!("{:#}", anyhow!(e))),
+        Err(_) => Err(format!("timed out after {}s", CHECK_TIMEOUT.as_secs())),
+    }
+}
+
+/// Print one line per check.
+pub fn print_report(results: &[CheckResult]) {
+    for result in results {
+        let outcome = match result.outcome {
+            Outcome::Pass => "PASS",
+            Outcome::Fail => "FAIL",
+            Outcome::Skip => "SKIP",
+        };
+        println!("{}  {:<8} {}", outcome, result.name, result.detail);
+    }
+}
+
+impl CheckResult {
+    fn pass(name: &'static str, detail: impl Display) -> Self {
+        CheckResult { name, outcome: Outcome::Pass, detail: detail.to_string() }
+    }
+
+    fn fail(name: &'static str, detail: impl Display) -> Self {
+        CheckResult { name, outcome: Outcome::Fail, detail: detail.to_string() }
+    }
+
+    fn skip(name: &'static str, detail: impl Display) -> Self {
+        CheckResult { name, outcome: Outcome::Skip, detail: detail.to_string() }
+    }
+}
@@ -1,9 +1,422 @@
-use clap::Parser;
+use chrono::{DateTime, Utc};
+use crate::config::Format;
+use clap::{Parser, Subcommand};
+use serde_yml::Value;
+use std::path::Path;
+
+/// The configuration files to look for in the working directory, if none is given.
+const DEFAULT_CONFIG_PATHS: [&str; 3] = ["config.yaml", "config.toml", "config.json"];
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct Args {
-    /// Path to the configuration file
-    #[arg(short = 'c', long, default_value = "config.yaml")]
-    pub config: String,
+    /// Path to the configuration file [default: config.yaml, config.toml or config.json, if one exists]
+    #[arg(short = 'c', long, env = "FCGIQ_CONFIG", global = true)]
+    pub config: Option<String>,
+
+    /// Format of the configuration file (yaml, toml or json) [default: determined by its extension]
+    #[arg(long, env = "FCGIQ_CONFIG_FORMAT", global = true, value_name = "FORMAT")]
+    pub config_format: Option<Format>,
+
+    #[command(flatten)]
+    pub overrides: ConfigOverrides,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Settings which override those in the configuration file. Each can also be set with an
+/// environment variable, named after its path in the configuration file with an `FCGIQ_` prefix.
+#[derive(clap::Args, Debug)]
+#[command(next_help_heading = "Configuration overrides")]
+pub struct ConfigOverrides {
+    #[arg(long, env = "FCGIQ_FASTCGI_ADDRESS", global = true, value_name = "ADDRESS")]
+    pub fastcgi_address: Option<String>,
+
+    #[arg(long, env = "FCGIQ_FASTCGI_PORT", global = true, value_name = "PORT")]
+    pub fastcgi_port: Option<u16>,
+
+    #[arg(long, env = "FCGIQ_FASTCGI_SCRIPT_PATH", global = true, value_name = "PATH")]
+    pub fastcgi_script_path: Option<String>,
+
+    #[arg(long, env = "FCGIQ_FASTCGI_MAX_PARALLEL_REQUESTS", global = true, value_name = "COUNT")]
+    pub fastcgi_max_parallel_requests: Option<u32>,
+
+    /// A CGI environment variable, as name=value (may be repeated, or comma-separated)
+    #[arg(
+        long, env = "FCGIQ_FASTCGI_CGI_ENVIRONMENT", global = true, value_name = "NAME=VALUE",
+        value_parser = parse_attribute, value_delimiter = ','
+    )]
+    pub fastcgi_cgi_environment: Vec<(String, String)>,
+
+    #[arg(long, env = "FCGIQ_FASTCGI_CIRCUIT_BREAKER_FAILURE_THRESHOLD", global = true, value_name = "COUNT")]
+    pub fastcgi_circuit_breaker_failure_threshold: Option<u32>,
+
+    #[arg(long, env = "FCGIQ_FASTCGI_CIRCUIT_BREAKER_COOLDOWN", global = true, value_name = "SECONDS")]
+    pub fastcgi_circuit_breaker_cooldown: Option<u64>,
+
+    #[arg(long, env = "FCGIQ_FASTCGI_CIRCUIT_BREAKER_MAX_COOLDOWN", global = true, value_name = "SECONDS")]
+    pub fastcgi_circuit_breaker_max_cooldown: Option<u64>,
+
+    #[arg(long, env = "FCGIQ_QUEUE_SQS_API_ENDPOINT_URL", global = true, value_name = "URL")]
+    pub queue_sqs_api_endpoint_url: Option<String>,
+
+    #[arg(long, env = "FCGIQ_QUEUE_SQS_QUEUE_URL", global = true, value_name = "URL")]
+    pub queue_sqs_queue_url: Option<String>,
+
+    #[arg(long, env = "FCGIQ_QUEUE_SQS_VISIBILITY_TIMEOUT", global = true, value_name = "SECONDS")]
+    pub queue_sqs_visibility_timeout: Option<i32>,
+
+    #[arg(long, env = "FCGIQ_QUEUE_S3_API_ENDPOINT_URL", global = true, value_name = "URL")]
+    pub queue_s3_api_endpoint_url: Option<String>,
+
+    #[arg(long, env = "FCGIQ_QUEUE_S3_FORCE_PATH_STYLE", global = true, value_name = "BOOL")]
+    pub queue_s3_force_path_style: Option<bool>,
+
+    #[arg(long, env = "FCGIQ_QUEUE_S3_DELETE_AFTER_ACKNOWLEDGE", global = true, value_name = "BOOL")]
+    pub queue_s3_delete_after_acknowledge: Option<bool>,
+
+    #[arg(long, env = "FCGIQ_QUEUE_S3_MAX_BYTES", global = true, value_name = "BYTES")]
+    pub queue_s3_max_bytes: Option<u64>,
+
+    /// Decoding steps (comma-separated), replacing those in the configuration file
+    #[arg(long, env = "FCGIQ_BODY_DECODING", global = true, value_name = "STEP", value_delimiter = ',')]
+    pub body_decoding: Vec<String>,
+
+    #[arg(long, env = "FCGIQ_MAX_DECODED_BYTES", global = true, value_name = "BYTES")]
+    pub max_decoded_bytes: Option<u64>,
+
+    #[arg(long, env = "FCGIQ_CONCURRENCY_KEY_SOURCE", global = true, value_name = "SOURCE")]
+    pub concurrency_key_source: Option<String>,
+
+    #[arg(long, env = "FCGIQ_CONCURRENCY_KEY_FIELD", global = true, value_name = "NAME")]
+    pub concurrency_key_field: Option<String>,
+
+    #[arg(long, env = "FCGIQ_CONCURRENCY_KEY_MAX_PARALLEL_REQUESTS", global = true, value_name = "COUNT")]
+    pub concurrency_key_max_parallel_requests: Option<u32>,
+
+    #[arg(long, env = "FCGIQ_CONCURRENCY_KEY_RETRY_DELAY", global = true, value_name = "SECONDS")]
+    pub concurrency_key_retry_delay: Option<i32>,
+
+    #[arg(long, env = "FCGIQ_RATE_LIMIT_RATE", global = true, value_name = "PER_SECOND")]
+    pub rate_limit_rate: Option<f64>,
+
+    #[arg(long, env = "FCGIQ_RATE_LIMIT_BURST", global = true, value_name = "COUNT")]
+    pub rate_limit_burst: Option<u32>,
+
+    #[arg(long, env = "FCGIQ_RATE_LIMIT_KEY_SOURCE", global = true, value_name = "SOURCE")]
+    pub rate_limit_key_source: Option<String>,
+
+    #[arg(long, env = "FCGIQ_RATE_LIMIT_KEY_FIELD", global = true, value_name = "NAME")]
+    pub rate_limit_key_field: Option<String>,
+
+    #[arg(long, env = "FCGIQ_RATE_LIMIT_KEY_RATE", global = true, value_name = "PER_SECOND")]
+    pub rate_limit_key_rate: Option<f64>,
+
+    #[arg(long, env = "FCGIQ_RATE_LIMIT_KEY_BURST", global = true, value_name = "COUNT")]
+    pub rate_limit_key_burst: Option<u32>,
+
+    #[arg(long, env = "FCGIQ_LOG_LEVEL", global = true, value_name = "LEVEL")]
+    pub log_level: Option<String>,
+
+    #[arg(long, env = "FCGIQ_LOG_FORMAT", global = true, value_name = "FORMAT")]
+    pub log_format: Option<String>,
+
+    #[arg(long, env = "FCGIQ_DRAIN_TIMEOUT", global = true, value_name = "SECONDS")]
+    pub drain_timeout: Option<u64>,
+
+    #[arg(long, env = "FCGIQ_HTTP_ADDRESS", global = true, value_name = "ADDRESS")]
+    pub http_address: Option<String>,
+
+    #[arg(long, env = "FCGIQ_HTTP_PORT", global = true, value_name = "PORT")]
+    pub http_port: Option<u16>,
+
+    #[arg(long, env = "FCGIQ_HTTP_LIVENESS_TIMEOUT", global = true, value_name = "SECONDS")]
+    pub http_liveness_timeout: Option<u64>,
+
+    #[arg(long, env = "FCGIQ_TRACING_OTLP_ENDPOINT", global = true, value_name = "URL")]
+    pub tracing_otlp_endpoint: Option<String>,
+
+    #[arg(long, env = "FCGIQ_TRACING_SERVICE_NAME", global = true, value_name = "NAME")]
+    pub tracing_service_name: Option<String>,
+
+    #[arg(long, env = "FCGIQ_AUDIT_FORMAT", global = true, value_name = "FORMAT")]
+    pub audit_format: Option<String>,
+
+    #[arg(long, env = "FCGIQ_AUDIT_PATH", global = true, value_name = "PATH")]
+    pub audit_path: Option<String>,
+
+    #[arg(long, env = "FCGIQ_AUDIT_MAX_BYTES", global = true, value_name = "BYTES")]
+    pub audit_max_bytes: Option<u64>,
+
+    #[arg(long, env = "FCGIQ_AUDIT_MAX_FILES", global = true, value_name = "COUNT")]
+    pub audit_max_files: Option<u32>,
+
+    /// CGI environment variables to redact (comma-separated), replacing those in the configuration file
+    #[arg(long, env = "FCGIQ_AUDIT_REDACT", global = true, value_name = "NAME", value_delimiter = ',')]
+    pub audit_redact: Vec<String>,
+
+    #[arg(long, env = "FCGIQ_AUDIT_MAX_OUTPUT_BYTES", global = true, value_name = "BYTES")]
+    pub audit_max_output_bytes: Option<usize>,
+
+    #[arg(long, env = "FCGIQ_ADMIN_ADDRESS", global = true, value_name = "ADDRESS")]
+    pub admin_address: Option<String>,
+
+    #[arg(long, env = "FCGIQ_ADMIN_PORT", global = true, value_name = "PORT")]
+    pub admin_port: Option<u16>,
+
+    #[arg(long, env = "FCGIQ_ADMIN_SOCKET_PATH", global = true, value_name = "PATH")]
+    pub admin_socket_path: Option<String>,
+
+    /// Any setting, as path=value, where the value is YAML (may be repeated, or given one per line
+    /// in the environment variable). Applied after the other overrides
+    #[arg(
+        long, env = "FCGIQ_SET", global = true, value_name = "PATH=VALUE",
+        value_parser = parse_setting, value_delimiter = '\n'
+    )]
+    pub set: Vec<(String, Value)>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Search the audit log, printing matching records as JSON lines
+    Audit(AuditArgs),
+
+    /// Send a message to the configured queue, printing its ID
+    Send(SendArgs),
+
+    /// Dispatch a single payload to the FastCGI server, without involving the queue, and print
+    /// the response
+    Exec(ExecArgs),
+
+    /// Check that the configuration is valid, and that the queue and FastCGI server are reachable
+    Check(CheckArgs),
+
+    /// Move messages from a dead-letter queue back to the configured queue
+    Redrive(RedriveArgs),
+
+    /// Show the number of messages in the queue and, with --admin, the state of a running instance
+    Stats(StatsArgs),
+
+    /// Work with the configuration file format
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Print a JSON Schema of the configuration file, for editors to validate against
+    Schema,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct AuditArgs {
+    /// Only show records for this message ID
+    #[arg(long)]
+    pub id: Option<String>,
+
+    /// Only show tasks received at or after this time (RFC 3339, e.g. 2024-01-01T00:00:00Z)
+    #[arg(long)]
+    pub since: Option<DateTime<Utc>>,
+
+    /// Only show tasks received before this time (RFC 3339)
+    #[arg(long)]
+    pub until: Option<DateTime<Utc>>,
+
+    /// The maximum number of records to show
+    #[arg(long)]
+    pub limit: Option<usize>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct SendArgs {
+    /// The message body. If neither this nor --file is given, the body is read from stdin
+    pub body: Option<String>,
+
+    /// Read the message body from a file
+    #[arg(short = 'f', long, conflicts_with = "body")]
+    pub file: Option<String>,
+
+    /// A string message attribute, as name=value (may be repeated)
+    #[arg(short = 'a', long = "attribute", value_name = "NAME=VALUE", value_parser = parse_attribute)]
+    pub attributes: Vec<(String, String)>,
+
+    /// The message group ID (FIFO queues only)
+    #[arg(long)]
+    pub group_id: Option<String>,
+
+    /// The message deduplication ID (FIFO queues only)
+    #[arg(long)]
+    pub deduplication_id: Option<String>,
+
+    /// The time (in seconds, up to 900) to delay delivery of the message
+    #[arg(long)]
+    pub delay: Option<i32>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ExecArgs {
+    /// The payload. If neither this nor --file is given, the payload is read from stdin
+    pub body: Option<String>,
+
+    /// Read the payload from a file
+    #[arg(short = 'f', long, conflicts_with = "body")]
+    pub file: Option<String>,
+
+    /// A metadata value (i.e. message attribute) available to the field mappings, as name=value
+    /// (may be repeated)
+    #[arg(short = 'm', long = "metadata", value_name = "NAME=VALUE", value_parser = parse_attribute)]
+    pub metadata: Vec<(String, String)>,
+
+    /// The message ID to give the payload
+    #[arg(long, default_value = "exec")]
+    pub id: String,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CheckArgs {
+    /// A script to run through the FastCGI server, which should return a successful status code
+    #[arg(long)]
+    pub ping_script: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct RedriveArgs {
+    /// The URL of the dead-letter queue to take messages from
+    #[arg(long)]
+    pub from: String,
+
+    /// Only redrive messages with this attribute value, as name=value (may be repeated)
+    #[arg(short = 'a', long = "attribute", value_name = "NAME=VALUE", value_parser = parse_attribute)]
+    pub attributes: Vec<(String, String)>,
+
+    /// Only redrive messages whose body is a JSON object with this property value, as name=value
+    /// (may be repeated)
+    #[arg(short = 'b', long = "body-field", value_name = "NAME=VALUE", value_parser = parse_attribute)]
+    pub body_fields: Vec<(String, String)>,
+
+    /// The maximum number of messages to redrive per second
+    #[arg(long)]
+    pub rate: Option<f64>,
+
+    /// The maximum number of messages to redrive
+    #[arg(long)]
+    pub limit: Option<usize>,
+
+    /// List the messages which would be redriven, without redriving them
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Dispatch messages straight to the FastCGI server instead of moving them, deleting them from
+    /// the dead-letter queue if the script succeeds
+    #[arg(long)]
+    pub direct: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct StatsArgs {
+    /// Also show the state of a running instance, using its admin API. Defaults to the admin API in
+    /// the configuration file; otherwise give a unix socket path (containing a '/') or host:port
+    #[arg(long, num_args = 0..=1, value_name = "SOCKET|HOST:PORT")]
+    pub admin: Option<Option<String>>,
+
+    /// Keep refreshing the stats, every this many seconds (default 5), until interrupted
+    #[arg(long, num_args = 0..=1, default_missing_value = "5", value_name = "SECONDS")]
+    pub watch: Option<u64>,
+}
+
+impl Args {
+    /// The configuration file to use: the one given, otherwise the first of `DEFAULT_CONFIG_PATHS`
+    /// which exists in the working directory.
+    pub fn config_path(&self) -> Option<String> {
+        self.config.clone().or_else(|| {
+            DEFAULT_CONFIG_PATHS.into_iter()
+                .find(|path| Path::new(path).exists())
+                .map(str::to_string)
+        })
+    }
+}
+
+impl ConfigOverrides {
+    /// The settings which were given, keyed by their dotted path in the configuration file.
+    pub fn values(&self) -> Vec<(String, Value)> {
+        let mut values = Vec::new();
+        let mut add = |path: &str, value: Option<Value>| {
+            if let Some(value) = value {
+                values.push((path.to_string(), value));
+            }
+        };
+        add("fastcgi.address", self.fastcgi_address.clone().map(Value::from));
+        add("fastcgi.port", self.fastcgi_port.map(Value::from));
+        add("fastcgi.script_path", self.fastcgi_script_path.clone().map(Value::from));
+        add("fastcgi.max_parallel_requests", self.fastcgi_max_parallel_requests.map(Value::from));
+        for (name, value) in &self.fastcgi_cgi_environment {
+            add(&format!("fastcgi.cgi_environment.{}", name), Some(Value::from(value.clone())));
+        }
+        add("fastcgi.circuit_breaker.failure_threshold", self.fastcgi_circuit_breaker_failure_threshold.map(Value::from));
+        add("fastcgi.circuit_breaker.cooldown", self.fastcgi_circuit_breaker_cooldown.map(Value::from));
+        add("fastcgi.circuit_breaker.max_cooldown", self.fastcgi_circuit_breaker_max_cooldown.map(Value::from));
+        add("queue.sqs.api_endpoint_url", self.queue_sqs_api_endpoint_url.clone().map(Value::from));
+        add("queue.sqs.queue_url", self.queue_sqs_queue_url.clone().map(Value::from));
+        add("queue.sqs.visibility_timeout", self.queue_sqs_visibility_timeout.map(Value::from));
+        add("queue.s3.api_endpoint_url", self.queue_s3_api_endpoint_url.clone().map(Value::from));
+        add("queue.s3.force_path_style", self.queue_s3_force_path_style.map(Value::from));
+        add("queue.s3.delete_after_acknowledge", self.queue_s3_delete_after_acknowledge.map(Value::from));
+        add("queue.s3.max_bytes", self.queue_s3_max_bytes.map(Value::from));
+        add("body_decoding", list(&self.body_decoding));
+        add("max_decoded_bytes", self.max_decoded_bytes.map(Value::from));
+        add("concurrency_key.source", self.concurrency_key_source.clone().map(Value::from));
+        add("concurrency_key.field", self.concurrency_key_field.clone().map(Value::from));
+        add("concurrency_key.max_parallel_requests", self.concurrency_key_max_parallel_requests.map(Value::from));
+        add("concurrency_key.retry_delay", self.concurrency_key_retry_delay.map(Value::from));
+        add("rate_limit.rate", self.rate_limit_rate.map(Value::from));
+        add("rate_limit.burst", self.rate_limit_burst.map(Value::from));
+        add("rate_limit.key.source", self.rate_limit_key_source.clone().map(Value::from));
+        add("rate_limit.key.field", self.rate_limit_key_field.clone().map(Value::from));
+        add("rate_limit.key.rate", self.rate_limit_key_rate.map(Value::from));
+        add("rate_limit.key.burst", self.rate_limit_key_burst.map(Value::from));
+        add("log_level", self.log_level.clone().map(Value::from));
+        add("log_format", self.log_format.clone().map(Value::from));
+        add("drain_timeout", self.drain_timeout.map(Value::from));
+        add("http.address", self.http_address.clone().map(Value::from));
+        add("http.port", self.http_port.map(Value::from));
+        add("http.liveness_timeout", self.http_liveness_timeout.map(Value::from));
+        add("tracing.otlp_endpoint", self.tracing_otlp_endpoint.clone().map(Value::from));
+        add("tracing.service_name", self.tracing_service_name.clone().map(Value::from));
+        add("audit.format", self.audit_format.clone().map(Value::from));
+        add("audit.path", self.audit_path.clone().map(Value::from));
+        add("audit.max_bytes", self.audit_max_bytes.map(Value::from));
+        add("audit.max_files", self.audit_max_files.map(Value::from));
+        add("audit.redact", list(&self.audit_redact));
+        add("audit.max_output_bytes", self.audit_max_output_bytes.map(|bytes| Value::from(bytes as u64)));
+        add("admin.address", self.admin_address.clone().map(Value::from));
+        add("admin.port", self.admin_port.map(Value::from));
+        add("admin.socket_path", self.admin_socket_path.clone().map(Value::from));
+        for (path, value) in &self.set {
+            add(path, Some(value.clone()));
+        }
+        values
+    }
+}
+
+/// A list setting, if any values were given.
+fn list(values: &[String]) -> Option<Value> {
+    (!values.is_empty()).then(|| Value::Sequence(values.iter().cloned().map(Value::from).collect()))
+}
+
+/// Parse a `--set` setting. An empty value is an empty string, rather than null.
+fn parse_setting(str: &str) -> Result<(String, Value), String> {
+    let (path, value) = parse_attribute(str)?;
+    if value.is_empty() {
+        return Ok((path, Value::from(value)));
+    }
+    let value = serde_yml::from_str(&value)
+        .map_err(|e| format!("invalid value for {}: {}", path, e))?;
+    Ok((path, value))
+}
+
+fn parse_attribute(str: &str) -> Result<(String, String), String> {
+    let (name, value) = str.split_once('=')
+        .ok_or_else(|| format!("expected NAME=VALUE, got '{}'", str))?;
+    Ok((name.to_string(), value.to_string()))
 }
@@ -1,25 +1,83 @@
+use crate::mapping;
 use log::LevelFilter;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use serde_yml;
+use serde_yml::{Mapping, Value};
 use std::collections::HashMap;
-use std::{fs, io, result};
+use std::fmt::{self, Display};
+use std::path::Path;
+use std::str::FromStr;
+use std::{env, fs, io, result};
 use thiserror::Error;
 
+/// The longest visibility timeout (in seconds) which SQS allows.
+pub const MAX_VISIBILITY_TIMEOUT: i32 = 43_200;
+
 //
 // Data structures
 //
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+/// Where the configuration comes from: a file (if any), and settings given on the command line or
+/// in environment variables, which take precedence.
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub path: Option<String>,
+    /// The format of the file, if it shouldn't be determined from the file's extension
+    pub format: Option<Format>,
+    /// Values keyed by their dotted path in the configuration file, e.g. `fastcgi.port`
+    pub overrides: Vec<(String, Value)>,
+}
+
+/// The format of a configuration file.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Format {
+    Yaml,
+    Toml,
+    Json,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     pub fastcgi: Fastcgi,
     pub queue: Queue,
     #[serde(default)]
+    /// Decoding steps to apply, in order, to the body of each queue item before it is mapped and dispatched
+    pub body_decoding: Vec<DecodingStep>,
+    #[serde(default = "Config::default_max_decoded_bytes")]
+    /// The largest body (in bytes) which a `Gzip` or `Zstd` decoding step may produce
+    pub max_decoded_bytes: u64,
+    #[serde(default)]
     pub field_mappings: FieldMappings,
+    #[serde(default)]
+    /// Limits the number of tasks with the same key (e.g. a tenant ID) which are processed in parallel
+    pub concurrency_key: Option<ConcurrencyKey>,
+    #[serde(default)]
+    /// Limits the rate at which tasks are dispatched, overall and for tasks with the same key
+    pub rate_limit: Option<RateLimit>,
     #[serde(default = "Config::default_log_level")]
     pub log_level: String,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    #[serde(default = "Config::default_drain_timeout")]
+    /// The time (in seconds) to wait for tasks in flight to finish when shutting down
+    pub drain_timeout: u64,
+    #[serde(default)]
+    /// Enables an HTTP listener for monitoring endpoints
+    pub http: Option<Http>,
+    #[serde(default)]
+    /// Enables the export of OpenTelemetry traces
+    pub tracing: Option<Tracing>,
+    #[serde(default)]
+    /// Enables a durable record of the outcome of every task
+    pub audit: Option<Audit>,
+    #[serde(default)]
+    /// Enables an HTTP API for controlling fcgiq while it is running
+    pub admin: Option<Admin>,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Fastcgi {
     pub address: String,
     pub port: u16,
@@ -28,14 +86,36 @@ pub struct Fastcgi {
     #[serde(default)]
     /// A mapping of CGI environment variable names (see https://www.rfc-editor.org/rfc/rfc3875.html#section-4) to default values
     pub cgi_environment: HashMap<String, String>,
+    #[serde(default)]
+    /// Stops receiving tasks while the FastCGI server isn't accepting connections
+    pub circuit_breaker: Option<CircuitBreaker>,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CircuitBreaker {
+    #[serde(default = "CircuitBreaker::default_failure_threshold")]
+    /// The number of consecutive connection failures after which the circuit breaker opens
+    pub failure_threshold: u32,
+    #[serde(default = "CircuitBreaker::default_cooldown")]
+    /// The time (in seconds) to wait after opening before probing the server
+    pub cooldown: u64,
+    #[serde(default = "CircuitBreaker::default_max_cooldown")]
+    /// The longest time (in seconds) to wait between probes, as the wait doubles after each failed probe
+    pub max_cooldown: u64,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Queue {
     pub sqs: Sqs,
+    #[serde(default)]
+    /// Enables retrieval of large payloads offloaded to S3 by the SQS Extended Client Library
+    pub s3: Option<S3>,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Sqs {
     #[serde(default)]
     pub api_endpoint_url: String,
@@ -43,19 +123,233 @@ pub struct Sqs {
     pub visibility_timeout: i32,
 }
 
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+pub enum DecodingStep {
+    /// Replace the body with the message inside an SNS notification envelope
+    SnsUnwrap,
+    Base64,
+    Gzip,
+    Zstd,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct S3 {
+    #[serde(default)]
+    pub api_endpoint_url: String,
+    #[serde(default)]
+    /// Address buckets as a path component of the URL, rather than a subdomain (needed for most
+    /// self-hosted S3-compatible services)
+    pub force_path_style: bool,
+    #[serde(default)]
+    /// Delete the offloaded payload once the queue item has been successfully processed
+    pub delete_after_acknowledge: bool,
+    #[serde(default = "S3::default_max_bytes")]
+    /// The largest offloaded payload (in bytes) which will be fetched
+    pub max_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines of text
+    #[default]
+    #[serde(alias = "Text")]
+    Text,
+    /// One JSON object per line, with structured fields
+    #[serde(alias = "Json")]
+    Json,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Http {
+    #[serde(default = "Http::default_address")]
+    pub address: String,
+    pub port: u16,
+    #[serde(default = "Http::default_liveness_timeout")]
+    /// The time (in seconds) after which the runner is considered stuck, if it hasn't made progress
+    pub liveness_timeout: u64,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Admin {
+    #[serde(default = "Http::default_address")]
+    pub address: String,
+    #[serde(default)]
+    /// The TCP port to listen on
+    pub port: Option<u16>,
+    #[serde(default)]
+    /// The path of a unix socket to listen on, instead of a TCP port
+    pub socket_path: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Tracing {
+    #[serde(default)]
+    /// The URL to send spans to, using OTLP over HTTP (e.g. `http://localhost:4318/v1/traces`)
+    pub otlp_endpoint: String,
+    #[serde(default = "Tracing::default_service_name")]
+    pub service_name: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Audit {
+    pub format: AuditFormat,
+    /// The file to write records to
+    pub path: String,
+    #[serde(default = "Audit::default_max_bytes")]
+    /// The size (in bytes) at which a JSONL file is rotated
+    pub max_bytes: u64,
+    #[serde(default = "Audit::default_max_files")]
+    /// The number of rotated JSONL files to keep
+    pub max_files: u32,
+    #[serde(default)]
+    /// CGI environment variables whose values are replaced with `[REDACTED]`
+    pub redact: Vec<String>,
+    #[serde(default = "Audit::default_max_output_bytes")]
+    /// The number of bytes of the script's stdout and stderr to record
+    pub max_output_bytes: usize,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+pub enum AuditFormat {
+    /// One JSON object per line, in a file which is rotated by size
+    Jsonl,
+    /// A table in an SQLite database
+    Sqlite,
+}
+
 pub type FieldMappings = HashMap<String, FieldMapping>;
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct FieldMapping {
-    pub source: FieldSource,
-    /// The CGI environment variable to set (see https://www.rfc-editor.org/rfc/rfc3875.html#section-4)
+    #[serde(default)]
+    pub source: Option<FieldSource>,
+    #[serde(default)]
+    /// What to read from the source: the name of the property, attribute, header or environment
+    /// variable; the time format for `Now`; or the value itself for `Static`. Not needed for
+    /// `ItemId`, `QueueName` and `ReceiveCount`.
     pub field: String,
+    #[serde(default)]
+    /// Compose the value from several sources, e.g. `/jobs/{body.type}/{meta.traceId}`. When set,
+    /// `source` and `field` are ignored.
+    pub template: Option<String>,
+    #[serde(default)]
+    /// A fallback value to use when the source doesn't provide one
+    pub default: Option<String>,
+    #[serde(default)]
+    /// If true, a task with no value for this field fails instead of being dispatched
+    pub required: bool,
+    #[serde(default)]
+    /// Transformations to apply, in order, to the value obtained from the source
+    pub transforms: Vec<Transform>,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
 pub enum FieldSource {
+    /// A property of the item's body, parsed as a JSON object
     BodyJson,
+    /// A message attribute or message system attribute
     Metadata,
+    /// The item's unique message ID
+    ItemId,
+    /// The name of the queue the item was received from
+    QueueName,
+    /// The number of times the item has been received from the queue (including this time)
+    ReceiveCount,
+    /// A variable from fcgiq's own process environment
+    Env,
+    /// A header from the script's response to the previous attempt at processing the item
+    Header,
+    /// The current time, using `field` as a strftime-style format (or RFC 3339 if empty)
+    Now,
+    /// The literal value of `field`
+    Static,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ConcurrencyKey {
+    /// Where to read each item's key from. Items without a key are only subject to the overall limit.
+    pub source: FieldSource,
+    #[serde(default)]
+    /// What to read from the source, as for a field mapping (e.g. the name of the body property
+    /// holding the tenant ID)
+    pub field: String,
+    /// The number of tasks with the same key which may be processed in parallel
+    pub max_parallel_requests: u32,
+    #[serde(default = "ConcurrencyKey::default_retry_delay")]
+    /// The time (in seconds) after which an item over its key's limit becomes visible on the queue again
+    pub retry_delay: i32,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    #[serde(default)]
+    /// The number of tasks per second which may be dispatched overall
+    pub rate: Option<f64>,
+    #[serde(default)]
+    /// The number of tasks which may be dispatched at once after a quiet period (defaults to the
+    /// rate, rounded up)
+    pub burst: Option<u32>,
+    #[serde(default)]
+    /// Limits the rate of tasks with the same key (e.g. the same route)
+    pub key: Option<KeyRateLimit>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct KeyRateLimit {
+    /// Where to read each item's key from. Items without a key are only subject to the overall rate.
+    pub source: FieldSource,
+    #[serde(default)]
+    /// What to read from the source, as for a field mapping (e.g. the name of the body property
+    /// holding the route)
+    pub field: String,
+    #[serde(default)]
+    /// The number of tasks per second which may be dispatched for each key not listed in `rates`
+    pub rate: Option<f64>,
+    #[serde(default)]
+    /// The burst size for each key not listed in `rates` (defaults to the rate, rounded up)
+    pub burst: Option<u32>,
+    #[serde(default)]
+    /// The rates for particular keys
+    pub rates: HashMap<String, Rate>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Rate {
+    /// The number of tasks per second
+    pub rate: f64,
+    #[serde(default)]
+    /// The burst size (defaults to the rate, rounded up)
+    pub burst: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub enum Transform {
+    Uppercase,
+    Lowercase,
+    Trim,
+    /// Replace the value with the given capture group (0 being the whole match) of a regular
+    /// expression. If the expression doesn't match, the value is discarded.
+    RegexExtract {
+        pattern: String,
+        #[serde(default)]
+        group: usize,
+    },
+    Base64Encode,
+    Base64Decode,
+    UrlEncode,
+    UrlDecode,
 }
 
 
@@ -64,19 +358,481 @@ pub enum FieldSource {
 //
 
 impl Config {
-    pub fn from_yaml_str(str: &str) -> Result<Self> {
-        let config: Config = serde_yml::from_str(str)?;
+    /// Parse a configuration file's contents in the given format, after expanding references to
+    /// environment variables and secret files (see `interpolate`).
+    pub fn from_str_with_format(str: &str, format: Format) -> Result<Self> {
+        Config::parse(&interpolate(str)?, format)
+    }
+
+    /// A JSON Schema describing the configuration file, for editors to validate against.
+    pub fn json_schema() -> schemars::Schema {
+        schemars::schema_for!(Config)
+    }
+
+    fn parse(str: &str, format: Format) -> Result<Self> {
+        let config: Config = match format {
+            Format::Yaml => serde_yml::from_str(str).map_err(Error::from_yaml)?,
+            Format::Json => {
+                let mut deserializer = serde_json::Deserializer::from_str(str);
+                let config = serde_path_to_error::deserialize(&mut deserializer)
+                    .map_err(|e| Error::from_json(e.path().to_string(), e.into_inner()))?;
+                deserializer.end().map_err(|e| Error::from_json(String::new(), e))?;
+                config
+            },
+            Format::Toml => {
+                let deserializer = toml::Deserializer::parse(str)
+                    .map_err(|e| Error::from_toml(String::new(), e, str))?;
+                serde_path_to_error::deserialize(deserializer)
+                    .map_err(|e| Error::from_toml(e.path().to_string(), e.into_inner(), str))?
+            },
+        };
+        config.validate()?;
         Ok(config)
     }
 
-    pub fn from_file(path: &str) -> Result<Self> {
-        let yaml = fs::read_to_string(path)?;
-        Config::from_yaml_str(&yaml)
+    /// Check for problems which can't be expressed through the structure of the data alone.
+    fn validate(&self) -> Result<()> {
+        if LevelFilter::from_str(&self.log_level).is_err() {
+            return Err(Error::Invalid(format!("log_level: unrecognized level '{}'", self.log_level)));
+        }
+        if self.fastcgi.max_parallel_requests == 0 {
+            return Err(Error::Invalid("fastcgi.max_parallel_requests: must be at least 1".to_string()));
+        }
+        if let Some(circuit_breaker) = &self.fastcgi.circuit_breaker {
+            if circuit_breaker.failure_threshold == 0 {
+                return Err(Error::Invalid("fastcgi.circuit_breaker.failure_threshold: must be at least 1".to_string()));
+            }
+            if circuit_breaker.cooldown == 0 {
+                return Err(Error::Invalid("fastcgi.circuit_breaker.cooldown: must be at least 1".to_string()));
+            }
+            if circuit_breaker.max_cooldown < circuit_breaker.cooldown {
+                return Err(Error::Invalid(
+                    "fastcgi.circuit_breaker.max_cooldown: must be at least the cooldown".to_string()
+                ));
+            }
+        }
+        if !is_valid_queue_url(&self.queue.sqs.queue_url) {
+            return Err(Error::Invalid(format!("queue.sqs.queue_url: invalid queue URL '{}'", self.queue.sqs.queue_url)));
+        }
+        for key in self.fastcgi.cgi_environment.keys() {
+            if !is_valid_cgi_variable_name(key) {
+                return Err(Error::Invalid(format!("fastcgi.cgi_environment.{}: invalid CGI variable name", key)));
+            }
+        }
+        if let Some(admin) = &self.admin {
+            if admin.port.is_some() == admin.socket_path.is_some() {
+                return Err(Error::Invalid("admin: exactly one of port or socket_path is required".to_string()));
+            }
+        }
+        if let Some(concurrency_key) = &self.concurrency_key {
+            if concurrency_key.source.requires_field() && concurrency_key.field.is_empty() {
+                return Err(Error::Invalid("concurrency_key.field: is required".to_string()));
+            }
+            if concurrency_key.max_parallel_requests == 0 {
+                return Err(Error::Invalid("concurrency_key.max_parallel_requests: must be at least 1".to_string()));
+            }
+            if !(0..=MAX_VISIBILITY_TIMEOUT).contains(&concurrency_key.retry_delay) {
+                return Err(Error::Invalid(
+                    format!("concurrency_key.retry_delay: must be between 0 and {}", MAX_VISIBILITY_TIMEOUT)
+                ));
+            }
+        }
+        if let Some(rate_limit) = &self.rate_limit {
+            validate_rate("rate_limit", rate_limit.rate, rate_limit.burst)?;
+            if let Some(key) = &rate_limit.key {
+                if key.source.requires_field() && key.field.is_empty() {
+                    return Err(Error::Invalid("rate_limit.key.field: is required".to_string()));
+                }
+                validate_rate("rate_limit.key", key.rate, key.burst)?;
+                for (name, rate) in key.rates.iter() {
+                    validate_rate(&format!("rate_limit.key.rates.{}", name), Some(rate.rate), rate.burst)?;
+                }
+            }
+        }
+        for (key, field_mapping) in self.field_mappings.iter() {
+            if !is_valid_cgi_variable_name(key) {
+                return Err(Error::Invalid(format!("field_mappings.{}: invalid CGI variable name", key)));
+            }
+            //Parses the template and compiles the transformations
+            mapping::Mapping::new(field_mapping)
+                .map_err(|e| Error::Invalid(format!("field_mappings.{}: {}", key, e)))?;
+            //A template's placeholders take the place of source and field
+            if field_mapping.template.is_some() {
+                continue;
+            }
+            match &field_mapping.source {
+                None if field_mapping.default.is_none() => return Err(Error::Invalid(
+                    format!("field_mappings.{}: one of source, template or default is required", key)
+                )),
+                Some(source) if source.requires_field() && field_mapping.field.is_empty() => {
+                    return Err(Error::Invalid(format!("field_mappings.{}: field is required", key)));
+                },
+                Some(FieldSource::Now) if !mapping::is_valid_time_format(&field_mapping.field) => {
+                    return Err(Error::Invalid(
+                        format!("field_mappings.{}: invalid time format '{}'", key, field_mapping.field)
+                    ));
+                },
+                _ => {},
+            }
+        }
+        Ok(())
     }
 
     fn default_log_level() -> String {
         LevelFilter::Info.to_string()
     }
+
+    fn default_max_decoded_bytes() -> u64 {
+        64 * 1024 * 1024
+    }
+
+    fn default_drain_timeout() -> u64 {
+        25
+    }
+}
+
+impl CircuitBreaker {
+    fn default_failure_threshold() -> u32 {
+        5
+    }
+
+    fn default_cooldown() -> u64 {
+        5
+    }
+
+    fn default_max_cooldown() -> u64 {
+        60
+    }
+}
+
+impl S3 {
+    fn default_max_bytes() -> u64 {
+        64 * 1024 * 1024
+    }
+}
+
+impl ConcurrencyKey {
+    fn default_retry_delay() -> i32 {
+        10
+    }
+}
+
+impl Source {
+    /// Read the configuration file (if any), and apply the overrides.
+    pub fn load(&self) -> Result<Config> {
+        let text = match &self.path {
+            Some(path) => fs::read_to_string(path)?,
+            None if self.overrides.is_empty() => return Err(Error::Invalid(
+                "no configuration file was found, and no settings were given on the command line or in environment variables".to_string()
+            )),
+            None => String::new(),
+        };
+        let format = self.format();
+        if self.overrides.is_empty() {
+            return Config::from_str_with_format(&text, format);
+        }
+
+        //Apply the overrides to the parsed document, then serialize it again, so that the values
+        //are deserialized in the same way as values in the file. JSON stands in for TOML, since
+        //their enum representations match. Locations in the serialized document wouldn't mean
+        //anything to the user, so they are left out of errors.
+        let text = interpolate(&text)?;
+        let mut document: Value = match format {
+            Format::Yaml => serde_yml::from_str(&text)?,
+            Format::Json => serde_json::from_str(&text).map_err(|e| Error::from_json(String::new(), e))?,
+            Format::Toml => toml::from_str(&text).map_err(|e| Error::from_toml(String::new(), e, &text))?,
+        };
+        for (path, value) in &self.overrides {
+            set_value(&mut document, path, value.clone())?;
+        }
+        let result = match format {
+            Format::Yaml => Config::parse(&serde_yml::to_string(&document)?, Format::Yaml),
+            Format::Toml | Format::Json => {
+                let json = serde_json::to_string(&document)
+                    .map_err(|e| Error::Invalid(e.to_string()))?;
+                Config::parse(&json, Format::Json)
+            },
+        };
+        result.map_err(|e| match e {
+            Error::Yaml(e) => Error::Invalid(without_location(&e)),
+            Error::Parse { path, message, .. } => Error::Parse { path, message, location: None },
+            Error::Unknown { kind, path, name, suggestion, .. } => {
+                Error::Unknown { kind, path, name, suggestion, location: None }
+            },
+            e => e,
+        })
+    }
+
+    /// The format of the file: as given, otherwise determined by the file's extension (YAML, unless
+    /// the extension is `.toml` or `.json`).
+    pub fn format(&self) -> Format {
+        let extension = self.path.as_deref()
+            .and_then(|path| Path::new(path).extension())
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+        self.format.unwrap_or(match extension.as_deref() {
+            Some("toml") => Format::Toml,
+            Some("json") => Format::Json,
+            _ => Format::Yaml,
+        })
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(str: &str) -> result::Result<Self, Self::Err> {
+        match str.to_ascii_lowercase().as_str() {
+            "yaml" | "yml" => Ok(Format::Yaml),
+            "toml" => Ok(Format::Toml),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("expected yaml, toml or json, got '{}'", str)),
+        }
+    }
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "{}", path),
+            None => write!(f, "the command line and environment"),
+        }
+    }
+}
+
+/// The message of a YAML error, without the line and column it occurred at.
+fn without_location(e: &serde_yml::Error) -> String {
+    let message = e.to_string();
+    match e.location() {
+        Some(location) => {
+            let suffix = format!(" at line {} column {}", location.line(), location.column());
+            message.strip_suffix(&suffix).unwrap_or(&message).to_string()
+        },
+        None => message,
+    }
+}
+
+/// Set the value at a dotted path in a YAML document, creating any sections which are missing.
+fn set_value(document: &mut Value, path: &str, value: Value) -> Result<()> {
+    let mut node = document;
+    let mut segments = path.split('.').peekable();
+    while let Some(segment) = segments.next() {
+        if node.is_null() {
+            *node = Value::Mapping(Mapping::new());
+        }
+        let Value::Mapping(mapping) = node else {
+            return Err(Error::Invalid(format!("{}: can't be overridden, since its parent isn't a section", path)));
+        };
+        let key = Value::String(segment.to_string());
+        if segments.peek().is_none() {
+            mapping.insert(key, value);
+            break;
+        }
+        node = mapping.entry(key).or_insert(Value::Null);
+    }
+    Ok(())
+}
+
+/// Expand `${VAR}` and `${VAR:-default}` references to environment variables, and replace
+/// `!file <path>` references with the contents of the file (minus any trailing newline), as a
+/// quoted string. This is done on the text of the file, line by line, so that types are inferred
+/// from the expanded values and errors point to the original line. Comments are left alone, and
+/// `$${` is an escaped `${`.
+fn interpolate(text: &str) -> Result<String> {
+    let mut output = String::with_capacity(text.len());
+    for (index, line) in text.split_inclusive('\n').enumerate() {
+        let (content, comment) = split_comment(line);
+        let content = expand_variables(content, index + 1)?;
+        let content = read_secret_files(&content, index + 1)?;
+        output.push_str(&content);
+        output.push_str(comment);
+    }
+    Ok(output)
+}
+
+/// Split a line of YAML into its content and its comment (if any). A `#` starts a comment if it
+/// follows whitespace (or starts the line), and isn't inside a quoted string.
+fn split_comment(line: &str) -> (&str, &str) {
+    let mut quote = None;
+    let mut previous = ' ';
+    for (i, c) in line.char_indices() {
+        match quote {
+            None if c == '#' && previous.is_whitespace() => return line.split_at(i),
+            None if (c == '\'' || c == '"') && (previous.is_whitespace() || "[{,:".contains(previous)) => {
+                quote = Some(c);
+            },
+            Some(q) if c == q => quote = None,
+            _ => {},
+        }
+        previous = c;
+    }
+    (line, "")
+}
+
+/// Replace `${VAR}` and `${VAR:-default}` with the value of the environment variable. The default
+/// is used if the variable is unset or empty. A variable without a default must be set.
+fn expand_variables(str: &str, line: usize) -> Result<String> {
+    let mut output = String::with_capacity(str.len());
+    let mut rest = str;
+    while let Some(start) = rest.find('$') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("$${") {
+            output.push_str("${");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after.find('}')
+                .ok_or_else(|| Error::Invalid(format!("line {}: unterminated variable reference", line)))?;
+            let (name, default) = match after[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&after[..end], None),
+            };
+            if !is_valid_cgi_variable_name(name) {
+                return Err(Error::Invalid(format!("line {}: invalid variable name '{}'", line, name)));
+            }
+            let value = match (env::var(name).ok().filter(|value| !value.is_empty()), default) {
+                (Some(value), _) => value,
+                (None, Some(default)) => default.to_string(),
+                (None, None) => env::var(name)
+                    .map_err(|_| Error::MissingVariable { name: name.to_string(), line })?,
+            };
+            output.push_str(&value);
+            rest = &after[end + 1..];
+        } else {
+            output.push('$');
+            rest = &rest[1..];
+        }
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Replace `!file <path>` with the contents of the file, as a double-quoted YAML string. Only a
+/// `!file` in value position (e.g. after `key: `, `- ` or `[`) is replaced, and never one inside
+/// a quoted string.
+fn read_secret_files(str: &str, line: usize) -> Result<String> {
+    const TAG: &str = "!file";
+    let mut output = String::with_capacity(str.len());
+    let mut quote = None;
+    let mut previous = ' ';
+    let mut rest = str;
+    while let Some(c) = rest.chars().next() {
+        match quote {
+            None if rest.starts_with(TAG) && is_value_position(&output) => {
+                let after = &rest[TAG.len()..];
+                let path_start = after.len() - after.trim_start_matches([' ', '\t']).len();
+                if path_start > 0 {
+                    let path = after[path_start..].split([' ', '\t', '\r', '\n', ',', ']', '}']).next().unwrap_or_default();
+                    if path.is_empty() {
+                        return Err(Error::Invalid(format!("line {}: !file requires a path", line)));
+                    }
+                    let contents = fs::read_to_string(path)
+                        .map_err(|source| Error::SecretFile { path: path.to_string(), line, source })?;
+                    let contents = contents.strip_suffix('\n').unwrap_or(&contents);
+                    let contents = contents.strip_suffix('\r').unwrap_or(contents);
+                    //A JSON string is also a valid double-quoted YAML string
+                    output.push_str(&serde_json::to_string(contents).unwrap_or_default());
+                    rest = &after[path_start + path.len()..];
+                    previous = '"';
+                    continue;
+                }
+            },
+            None if (c == '\'' || c == '"') && (previous.is_whitespace() || "[{,:=".contains(previous)) => {
+                quote = Some(c);
+            },
+            Some(q) if c == q => quote = None,
+            _ => {},
+        }
+        output.push(c);
+        previous = c;
+        rest = &rest[c.len_utf8()..];
+    }
+    Ok(output)
+}
+
+/// Whether a value may start after the given text: at the start of the line, or after a key (in
+/// YAML, TOML or JSON), a list item marker, or an opening bracket or separator in a flow
+/// collection.
+fn is_value_position(before: &str) -> bool {
+    let trimmed = before.trim_end_matches([' ', '\t']);
+    let separated = before.is_empty() || trimmed.len() < before.len() || trimmed.ends_with(['[', '{', ',']);
+    separated && (trimmed.is_empty() || trimmed.ends_with([':', '-', '=', '[', '{', ',']))
+}
+
+/// Whether a name is usable as a CGI environment variable: letters, digits and underscores, not
+/// starting with a digit.
+fn is_valid_cgi_variable_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Check that a rate (if given) is a positive number of tasks per second, and that a burst size is
+/// only given alongside a rate.
+fn validate_rate(path: &str, rate: Option<f64>, burst: Option<u32>) -> Result<()> {
+    match (rate, burst) {
+        (Some(rate), _) if !(rate.is_finite() && rate > 0.0) => {
+            Err(Error::Invalid(format!("{}.rate: must be greater than 0", path)))
+        },
+        (_, Some(0)) => Err(Error::Invalid(format!("{}.burst: must be at least 1", path))),
+        (None, Some(_)) => Err(Error::Invalid(format!("{}.burst: requires a rate", path))),
+        _ => Ok(()),
+    }
+}
+
+/// Whether a queue URL is an HTTP(S) URL with a host and a queue name, e.g.
+/// `https://sqs.us-east-1.amazonaws.com/177715257436/MyQueue`.
+fn is_valid_queue_url(url: &str) -> bool {
+    let Some(rest) = url.strip_prefix("https://").or_else(|| url.strip_prefix("http://")) else {
+        return false;
+    };
+    match rest.trim_end_matches('/').split_once('/') {
+        Some((host, path)) => !host.is_empty() && !path.is_empty() && !url.contains(char::is_whitespace),
+        None => false,
+    }
+}
+
+impl Http {
+    fn default_address() -> String {
+        "127.0.0.1".to_string()
+    }
+
+    fn default_liveness_timeout() -> u64 {
+        60
+    }
+}
+
+impl Tracing {
+    fn default_service_name() -> String {
+        "fcgiq".to_string()
+    }
+}
+
+impl Audit {
+    fn default_max_bytes() -> u64 {
+        100 * 1024 * 1024
+    }
+
+    fn default_max_files() -> u32 {
+        5
+    }
+
+    fn default_max_output_bytes() -> usize {
+        4096
+    }
+}
+
+impl FieldSource {
+    /// Whether a mapping using this source needs a `field` to be specified.
+    pub fn requires_field(&self) -> bool {
+        matches!(self, Self::BodyJson | Self::Metadata | Self::Env | Self::Header | Self::Static)
+    }
+
+    /// Whether values from this source depend on the item's body, so can only be read once its
+    /// payload has been fetched and decoded.
+    pub fn reads_body(&self) -> bool {
+        matches!(self, Self::BodyJson)
+    }
 }
 
 
@@ -91,6 +847,308 @@ pub enum Error {
 
     #[error(transparent)]
     Yaml(#[from] serde_yml::Error),
+
+    #[error("{0}")]
+    Invalid(String),
+
+    #[error("line {line}: environment variable {name} is not set, and no default was given")]
+    MissingVariable { name: String, line: usize },
+
+    #[error("line {line}: unable to read secret file {path}")]
+    SecretFile { path: String, line: usize, #[source] source: io::Error },
+
+    #[error("{}{message}{}", prefix(.path), at(.location))]
+    Parse {
+        path: String,
+        message: String,
+        location: Option<(usize, usize)>,
+    },
+
+    #[error("{}unknown {kind} `{name}`{}{}", prefix(.path), did_you_mean(.suggestion), at(.location))]
+    Unknown {
+        /// `field` or `variant`
+        kind: &'static str,
+        /// The path to the section containing the unknown name, e.g. `queue.sqs`
+        path: String,
+        name: String,
+        /// The closest expected name, if any is close enough to be a likely typo
+        suggestion: Option<String>,
+        /// The line and column where the name appears
+        location: Option<(usize, usize)>,
+    },
+}
+
+impl Error {
+    /// Convert a YAML error into `Error::Unknown` if it's about an unknown field or enum variant,
+    /// suggesting the closest expected name.
+    fn from_yaml(e: serde_yml::Error) -> Self {
+        //serde_yml puts the path at the start of the message, e.g. "queue.sqs: unknown field ..."
+        let message = without_location(&e);
+        let location = e.location().map(|location| (location.line(), location.column()));
+        let Some(start) = message.find("unknown ") else {
+            return Error::Yaml(e);
+        };
+        let path = message[..start].trim_end_matches([':', ' ']).replace("\\[", "[").replace("\\]", "]");
+        Error::unknown(path, &message[start..], location).unwrap_or(Error::Yaml(e))
+    }
+
+    /// Convert a JSON error into `Error::Unknown` or `Error::Parse`.
+    fn from_json(path: String, e: serde_json::Error) -> Self {
+        let location = Some((e.line(), e.column())).filter(|(line, _)| *line > 0);
+        let message = e.to_string();
+        let message = match location {
+            Some((line, column)) => {
+                let suffix = format!(" at line {} column {}", line, column);
+                message.strip_suffix(&suffix).unwrap_or(&message).to_string()
+            },
+            None => message,
+        };
+        Error::unknown(path.clone(), &message, location)
+            .unwrap_or(Error::Parse { path, message, location })
+    }
+
+    /// Convert a TOML error into `Error::Unknown` or `Error::Parse`.
+    fn from_toml(path: String, e: toml::de::Error, text: &str) -> Self {
+        let location = e.span().map(|span| line_and_column(text, span.start));
+        let message = e.message().trim_end().to_string();
+        Error::unknown(path.clone(), &message, location)
+            .unwrap_or(Error::Parse { path, message, location })
+    }
+
+    /// Make an `Error::Unknown` if a deserialization error message is about an unknown field or
+    /// enum variant, suggesting the closest expected name.
+    fn unknown(path: String, message: &str, location: Option<(usize, usize)>) -> Option<Self> {
+        let (kind, rest) = ["field", "variant"].into_iter()
+            .find_map(|kind| Some((kind, message.strip_prefix(&format!("unknown {} `", kind))?)))?;
+        let end = rest.find('`')?;
+        let name = &rest[..end];
+        //serde_path_to_error includes the unknown field in the path
+        let path = match path.strip_suffix(name) {
+            Some(parent) if parent.is_empty() || parent.ends_with('.') => parent.trim_end_matches('.').to_string(),
+            _ => path,
+        };
+        //The rest of the message lists the expected names, e.g. ", expected one of `a`, `b`"
+        let expected: Vec<&str> = rest[end + 1..].split('`').skip(1).step_by(2).collect();
+        Some(Error::Unknown {
+            kind,
+            path,
+            name: name.to_string(),
+            suggestion: closest_match(name, &expected).map(str::to_string),
+            location,
+        })
+    }
+}
+
+/// The (1-based) line and column of a byte offset in some text.
+fn line_and_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    (before.matches('\n').count() + 1, before[line_start..].chars().count() + 1)
+}
+
+/// The candidate most similar to the given name, if any is similar enough to be a likely typo.
+fn closest_match<'a>(name: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    candidates.iter()
+        .map(|candidate| (strsim::jaro_winkler(name, candidate), *candidate))
+        .filter(|(similarity, _)| *similarity >= 0.8)
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, candidate)| candidate)
+}
+
+fn prefix(path: &str) -> String {
+    //serde_path_to_error represents the root as "."
+    if path.is_empty() || path == "." { String::new() } else { format!("{}: ", path) }
+}
+
+fn did_you_mean(suggestion: &Option<String>) -> String {
+    suggestion.as_ref().map(|suggestion| format!(" (did you mean `{}`?)", suggestion)).unwrap_or_default()
+}
+
+fn at(location: &Option<(usize, usize)>) -> String {
+    location.map(|(line, column)| format!(" at line {} column {}", line, column)).unwrap_or_default()
 }
 
 pub type Result<T> = result::Result<T, Error>;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINIMAL: &str = "fastcgi:
+  address: 127.0.0.1
+  port: 9000
+  script_path: /srv/app/task.php
+  max_parallel_requests: 1
+queue:
+  sqs:
+    queue_url: https://sqs.us-east-1.amazonaws.com/177715257436/MyQueue
+    visibility_timeout: 30
+";
+
+    #[test]
+    fn log_format_accepts_either_case() {
+        for (value, expected) in [("text", LogFormat::Text), ("Json", LogFormat::Json), ("json", LogFormat::Json)] {
+            let config = Config::from_str_with_format(&format!("{}log_format: {}\n", MINIMAL, value), Format::Yaml).unwrap();
+            assert_eq!(config.log_format, expected);
+        }
+    }
+
+    #[test]
+    fn schema_lists_lowercase_log_formats() {
+        let schema = serde_json::to_value(Config::json_schema()).unwrap();
+        let values: Vec<&str> = schema["$defs"]["LogFormat"]["oneOf"].as_array().unwrap().iter()
+            .map(|variant| variant["const"].as_str().unwrap())
+            .collect();
+        assert_eq!(values, ["text", "json"]);
+    }
+
+    #[test]
+    fn unknown_field_suggests_closest_name() {
+        let text = MINIMAL.replace("visibility_timeout", "visibilty_timeout");
+        match Config::from_str_with_format(&text, Format::Yaml) {
+            Err(Error::Unknown { kind, path, name, suggestion, location }) => {
+                assert_eq!(kind, "field");
+                assert_eq!(path, "queue.sqs");
+                assert_eq!(name, "visibilty_timeout");
+                assert_eq!(suggestion.as_deref(), Some("visibility_timeout"));
+                assert_eq!(location.map(|(line, _)| line), Some(9));
+            },
+            result => panic!("unexpected result: {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn unknown_variant_suggests_closest_name() {
+        let text = format!("{}log_format: jsn\n", MINIMAL);
+        match Config::from_str_with_format(&text, Format::Yaml) {
+            Err(Error::Unknown { kind, name, suggestion, .. }) => {
+                assert_eq!(kind, "variant");
+                assert_eq!(name, "jsn");
+                assert_eq!(suggestion.as_deref(), Some("json"));
+            },
+            result => panic!("unexpected result: {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn unknown_field_without_close_match_has_no_suggestion() {
+        let text = format!("{}completely_different: 1\n", MINIMAL);
+        assert!(matches!(
+            Config::from_str_with_format(&text, Format::Yaml),
+            Err(Error::Unknown { suggestion: None, .. })
+        ));
+    }
+
+    #[test]
+    fn closest_match_requires_similarity() {
+        assert_eq!(closest_match("queue_ulr", &["queue_url", "visibility_timeout"]), Some("queue_url"));
+        assert_eq!(closest_match("xyz", &["queue_url", "visibility_timeout"]), None);
+        assert_eq!(closest_match("anything", &[]), None);
+    }
+
+    #[test]
+    fn split_comment_at_hash_after_whitespace() {
+        assert_eq!(split_comment("key: value # comment\n"), ("key: value ", "# comment\n"));
+        assert_eq!(split_comment("# whole line\n"), ("", "# whole line\n"));
+        assert_eq!(split_comment("url: http://host/#fragment\n"), ("url: http://host/#fragment\n", ""));
+    }
+
+    #[test]
+    fn split_comment_ignores_hash_inside_quotes() {
+        assert_eq!(split_comment("key: \"a # b\" # comment"), ("key: \"a # b\" ", "# comment"));
+        assert_eq!(split_comment("key: 'a # b'"), ("key: 'a # b'", ""));
+        assert_eq!(split_comment("list: [\"#\", '#'] # comment"), ("list: [\"#\", '#'] ", "# comment"));
+        //An apostrophe inside a word doesn't start a quoted string
+        assert_eq!(split_comment("key: it's # comment"), ("key: it's ", "# comment"));
+    }
+
+    #[test]
+    fn read_secret_files_in_value_position() {
+        let path = env::temp_dir().join(format!("fcgiq-test-secret-{}", std::process::id()));
+        fs::write(&path, "s3cret\n").unwrap();
+        let path = path.to_str().unwrap();
+        for (line, expected) in [
+            (format!("key: !file {}", path), "key: \"s3cret\""),
+            (format!("- !file {}", path), "- \"s3cret\""),
+            (format!("!file {}", path), "\"s3cret\""),
+            (format!("key: [!file {0}, !file {0}]", path), "key: [\"s3cret\", \"s3cret\"]"),
+            (format!("key = !file {}", path), "key = \"s3cret\""),
+            (format!("\"key\": !file {}", path), "\"key\": \"s3cret\""),
+        ] {
+            assert_eq!(read_secret_files(&line, 1).unwrap(), expected);
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn read_secret_files_ignores_tag_inside_quotes_and_text() {
+        for line in [
+            "description: \"see !file /nonexistent\"",
+            "description: 'see !file /nonexistent'",
+            "description: see !file /nonexistent",
+            "list: [\"a\", \"!file /nonexistent\"]",
+            "key: !filename",
+        ] {
+            assert_eq!(read_secret_files(line, 1).unwrap(), line);
+        }
+    }
+
+    #[test]
+    fn read_secret_files_requires_file() {
+        assert!(matches!(
+            read_secret_files("key: !file /nonexistent/fcgiq-secret", 4),
+            Err(Error::SecretFile { line: 4, .. })
+        ));
+    }
+
+    #[test]
+    fn expand_variables_uses_environment() {
+        env::set_var("FCGIQ_TEST_EXPAND_SET", "value");
+        assert_eq!(expand_variables("a: ${FCGIQ_TEST_EXPAND_SET}", 1).unwrap(), "a: value");
+        assert_eq!(expand_variables("a: ${FCGIQ_TEST_EXPAND_SET:-default}", 1).unwrap(), "a: value");
+    }
+
+    #[test]
+    fn expand_variables_uses_default_if_unset_or_empty() {
+        env::set_var("FCGIQ_TEST_EXPAND_EMPTY", "");
+        assert_eq!(expand_variables("${FCGIQ_TEST_EXPAND_UNSET:-default}", 1).unwrap(), "default");
+        assert_eq!(expand_variables("${FCGIQ_TEST_EXPAND_EMPTY:-default}", 1).unwrap(), "default");
+        assert_eq!(expand_variables("${FCGIQ_TEST_EXPAND_UNSET:-}", 1).unwrap(), "");
+        assert_eq!(expand_variables("${FCGIQ_TEST_EXPAND_EMPTY}", 1).unwrap(), "");
+    }
+
+    #[test]
+    fn expand_variables_requires_missing_variable() {
+        match expand_variables("a: ${FCGIQ_TEST_EXPAND_MISSING}", 3) {
+            Err(Error::MissingVariable { name, line }) => {
+                assert_eq!(name, "FCGIQ_TEST_EXPAND_MISSING");
+                assert_eq!(line, 3);
+            },
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn expand_variables_leaves_escapes_and_lone_dollars() {
+        assert_eq!(expand_variables("$${NAME} costs $5", 1).unwrap(), "${NAME} costs $5");
+        assert!(matches!(expand_variables("${NAME", 1), Err(Error::Invalid(_))));
+        assert!(matches!(expand_variables("${NOT-VALID}", 1), Err(Error::Invalid(_))));
+    }
+
+    #[test]
+    fn interpolate_skips_comments() {
+        env::set_var("FCGIQ_TEST_INTERPOLATE", "value");
+        let text = "a: ${FCGIQ_TEST_INTERPOLATE} # ${FCGIQ_TEST_INTERPOLATE_MISSING}\nb: \"#${FCGIQ_TEST_INTERPOLATE}\"\n";
+        assert_eq!(
+            interpolate(text).unwrap(),
+            "a: value # ${FCGIQ_TEST_INTERPOLATE_MISSING}\nb: \"#value\"\n"
+        );
+    }
+
+    #[test]
+    fn interpolate_reports_line_of_missing_variable() {
+        let text = "a: 1\n# ${FCGIQ_TEST_INTERPOLATE_MISSING}\nb: ${FCGIQ_TEST_INTERPOLATE_MISSING}\n";
+        assert!(matches!(interpolate(text), Err(Error::MissingVariable { line: 3, .. })));
+    }
+}
@@ -0,0 +1,164 @@
+use crate::item::Item;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+/// The period over which recent task outcomes are counted.
+pub const RECENT_WINDOW: Duration = Duration::from_secs(60);
+
+//
+// Data structures
+//
+
+/// Settings and requests which can be changed while fcgiq is running (via the admin API), and
+/// the tasks currently being processed.
+pub struct Control {
+    settings: watch::Sender<Settings>,
+    in_flight: Mutex<HashMap<String, InFlightTask>>,
+    finished: Mutex<VecDeque<(Instant, bool)>>,
+    drain: CancellationToken,
+}
+
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct Settings {
+    /// Whether the runner should stop polling for new tasks
+    pub paused: bool,
+    /// The number of tasks which may be processed in parallel
+    pub max_tasks: usize,
+}
+
+struct InFlightTask {
+    queue: String,
+    receipt_handle: Option<String>,
+    started: Instant,
+}
+
+/// A task which is currently being processed.
+#[derive(Serialize, Deserialize)]
+pub struct InFlightStatus {
+    pub id: String,
+    pub queue: String,
+    pub elapsed_seconds: f64,
+}
+
+/// The number of tasks which finished within the last `window_seconds`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct RecentOutcomes {
+    pub window_seconds: u64,
+    pub succeeded: usize,
+    pub failed: usize,
+}
+
+
+//
+// Functions
+//
+
+impl Control {
+    pub fn new(max_tasks: usize) -> Self {
+        Control {
+            settings: watch::Sender::new(Settings { paused: false, max_tasks }),
+            in_flight: Mutex::new(HashMap::new()),
+            finished: Mutex::new(VecDeque::new()),
+            drain: CancellationToken::new(),
+        }
+    }
+
+    pub fn settings(&self) -> Settings {
+        *self.settings.borrow()
+    }
+
+    /// Watch for changes to the settings.
+    pub fn subscribe(&self) -> watch::Receiver<Settings> {
+        self.settings.subscribe()
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.settings.send_modify(|settings| settings.paused = paused);
+    }
+
+    pub fn set_max_tasks(&self, max_tasks: usize) {
+        self.settings.send_modify(|settings| settings.max_tasks = max_tasks);
+    }
+
+    /// Request that fcgiq stops polling for new tasks, and exits once the in-flight tasks finish.
+    pub fn request_drain(&self) {
+        self.drain.cancel();
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.drain.is_cancelled()
+    }
+
+    /// Wait until a drain is requested.
+    pub async fn drain_requested(&self) {
+        self.drain.cancelled().await
+    }
+
+    /// Record that processing of a task has started.
+    pub fn start_task(&self, item: &Item) {
+        self.in_flight.lock().unwrap().insert(item.id.clone(), InFlightTask {
+            queue: item.queue.clone(),
+            receipt_handle: item.metadata.get("receipt_handle").cloned(),
+            started: Instant::now(),
+        });
+    }
+
+    /// Record that processing of a task has finished, and whether it succeeded.
+    pub fn finish_task(&self, id: &str, succeeded: bool) {
+        self.in_flight.lock().unwrap().remove(id);
+        let mut finished = self.finished.lock().unwrap();
+        finished.push_back((Instant::now(), succeeded));
+        prune(&mut finished);
+    }
+
+    /// Record that a task was handed back to the queue without being processed, so it has no
+    /// outcome.
+    pub fn defer_task(&self, id: &str) {
+        self.in_flight.lock().unwrap().remove(id);
+    }
+
+    /// Count the tasks which succeeded and failed within the last `RECENT_WINDOW`.
+    pub fn recent_outcomes(&self) -> RecentOutcomes {
+        let mut finished = self.finished.lock().unwrap();
+        prune(&mut finished);
+        let succeeded = finished.iter().filter(|(_, succeeded)| *succeeded).count();
+        RecentOutcomes {
+            window_seconds: RECENT_WINDOW.as_secs(),
+            succeeded,
+            failed: finished.len() - succeeded,
+        }
+    }
+
+    /// List the tasks currently being processed, longest-running first.
+    pub fn in_flight(&self) -> Vec<InFlightStatus> {
+        let in_flight = self.in_flight.lock().unwrap();
+        let mut tasks: Vec<_> = in_flight.iter()
+            .map(|(id, task)| (task.started, InFlightStatus {
+                id: id.clone(),
+                queue: task.queue.clone(),
+                elapsed_seconds: task.started.elapsed().as_secs_f64(),
+            }))
+            .collect();
+        tasks.sort_by_key(|(started, _)| *started);
+        tasks.into_iter().map(|(_, status)| status).collect()
+    }
+
+    /// Remove all tasks from the in-flight list, returning their IDs and receipt handles.
+    pub fn take_in_flight(&self) -> Vec<(String, Option<String>)> {
+        self.in_flight.lock().unwrap()
+            .drain()
+            .map(|(id, task)| (id, task.receipt_handle))
+            .collect()
+    }
+}
+
+/// Forget the outcomes of tasks which finished before the start of the `RECENT_WINDOW`.
+fn prune(finished: &mut VecDeque<(Instant, bool)>) {
+    while finished.front().is_some_and(|(at, _)| at.elapsed() > RECENT_WINDOW) {
+        finished.pop_front();
+    }
+}
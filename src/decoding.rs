@@ -0,0 +1,190 @@
+use crate::config::DecodingStep;
+use crate::item::Item;
+use base64::prelude::*;
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::Read;
+use std::{io, result};
+use thiserror::Error;
+
+//
+// Data structures
+//
+
+/// The JSON envelope which SNS wraps around a notification when delivering it to an SQS queue
+/// (unless raw message delivery is enabled on the subscription).
+///
+/// See: https://docs.aws.amazon.com/sns/latest/dg/sns-message-and-json-formats.html
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SnsEnvelope {
+    message: String,
+    topic_arn: Option<String>,
+    subject: Option<String>,
+    #[serde(default)]
+    message_attributes: HashMap<String, SnsMessageAttribute>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SnsMessageAttribute {
+    value: String,
+}
+
+
+//
+// Functions
+//
+
+/// Apply a sequence of decoding steps to the body of a queue item, replacing the body with the
+/// decoded payload. Decompression fails if it would produce more than `max_bytes`.
+pub fn decode(item: &mut Item, steps: &[DecodingStep], max_bytes: u64) -> Result<()> {
+    for step in steps.iter() {
+        log::debug!(task_id = item.id; "decoding body: {:?}", step);
+        match step {
+            DecodingStep::SnsUnwrap => sns_unwrap(item)?,
+            DecodingStep::Base64 => {
+                let encoded: Vec<u8> = item.data.iter()
+                    .filter(|b| !b.is_ascii_whitespace())
+                    .copied()
+                    .collect();
+                item.data = BASE64_STANDARD.decode(encoded)?;
+            },
+            DecodingStep::Gzip => {
+                item.data = read_limited(GzDecoder::new(item.data.as_slice()), max_bytes, Error::Gzip)?;
+            },
+            DecodingStep::Zstd => {
+                let decoder = zstd::Decoder::new(item.data.as_slice()).map_err(Error::Zstd)?;
+                item.data = read_limited(decoder, max_bytes, Error::Zstd)?;
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Read everything from a decoder, failing if there is more than `max_bytes`.
+fn read_limited(decoder: impl Read, max_bytes: u64, error: fn(io::Error) -> Error) -> Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    decoder.take(max_bytes.saturating_add(1)).read_to_end(&mut decoded)
+        .map_err(error)?;
+    if decoded.len() as u64 > max_bytes {
+        return Err(Error::TooLarge(max_bytes));
+    }
+    Ok(decoded)
+}
+
+/// Replace the body of the item with the message inside its SNS envelope, and promote the
+/// envelope's topic, subject and message attributes into the item's metadata. SNS message
+/// attributes never replace SQS attributes of the same name.
+fn sns_unwrap(item: &mut Item) -> Result<()> {
+    let envelope: SnsEnvelope = serde_json::from_slice(&item.data)?;
+    if let Some(topic_arn) = envelope.topic_arn {
+        item.metadata.insert("TopicArn".to_string(), topic_arn);
+    }
+    if let Some(subject) = envelope.subject {
+        item.metadata.insert("Subject".to_string(), subject);
+    }
+    for (key, attribute) in envelope.message_attributes {
+        log::debug!(task_id = item.id; "SNS MessageAttribute[{}] = {}", key, attribute.value);
+        item.metadata.entry(key).or_insert(attribute.value);
+    }
+    item.data = envelope.message.into_bytes();
+    Ok(())
+}
+
+
+//
+// Error handling
+//
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("body is not a valid SNS envelope")]
+    SnsEnvelope(#[from] serde_json::Error),
+
+    #[error("body is not valid base64")]
+    Base64(#[from] base64::DecodeError),
+
+    #[error("body is not valid gzip data")]
+    Gzip(#[source] io::Error),
+
+    #[error("body is not valid zstd data")]
+    Zstd(#[source] io::Error),
+
+    #[error("decoded body is larger than {0} bytes")]
+    TooLarge(u64),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    const PAYLOAD: &[u8] = br#"{"job":"/reports/generate","tenant":"acme"}"#;
+
+    fn item(data: Vec<u8>) -> Item {
+        Item {
+            id: "msg-1".to_string(),
+            queue: "jobs".to_string(),
+            data,
+            metadata: HashMap::new(),
+            message_attributes: HashMap::new(),
+            previous_response_headers: HashMap::new(),
+        }
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zstd(data: &[u8]) -> Vec<u8> {
+        zstd::encode_all(data, 0).unwrap()
+    }
+
+    #[test]
+    fn decompresses_payload_at_limit() {
+        let limit = PAYLOAD.len() as u64;
+        for (step, compressed) in [(DecodingStep::Gzip, gzip(PAYLOAD)), (DecodingStep::Zstd, zstd(PAYLOAD))] {
+            let mut queue_item = item(compressed);
+            decode(&mut queue_item, &[step], limit).unwrap();
+            assert_eq!(queue_item.data, PAYLOAD);
+        }
+    }
+
+    #[test]
+    fn rejects_payload_over_limit() {
+        let limit = PAYLOAD.len() as u64 - 1;
+        for (step, compressed) in [(DecodingStep::Gzip, gzip(PAYLOAD)), (DecodingStep::Zstd, zstd(PAYLOAD))] {
+            let mut queue_item = item(compressed);
+            assert!(matches!(decode(&mut queue_item, &[step], limit), Err(Error::TooLarge(max)) if max == limit));
+        }
+    }
+
+    #[test]
+    fn limit_applies_after_earlier_steps() {
+        let encoded = BASE64_STANDARD.encode(gzip(PAYLOAD)).into_bytes();
+        let steps = [DecodingStep::Base64, DecodingStep::Gzip];
+        let mut queue_item = item(encoded.clone());
+        decode(&mut queue_item, &steps, PAYLOAD.len() as u64).unwrap();
+        assert_eq!(queue_item.data, PAYLOAD);
+
+        let mut queue_item = item(encoded);
+        assert!(matches!(decode(&mut queue_item, &steps, 10), Err(Error::TooLarge(10))));
+    }
+
+    #[test]
+    fn rejects_invalid_compressed_data() {
+        let mut queue_item = item(PAYLOAD.to_vec());
+        assert!(matches!(decode(&mut queue_item, &[DecodingStep::Gzip], 1024), Err(Error::Gzip(_))));
+        let mut queue_item = item(PAYLOAD.to_vec());
+        assert!(matches!(decode(&mut queue_item, &[DecodingStep::Zstd], 1024), Err(Error::Zstd(_))));
+    }
+}
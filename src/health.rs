@@ -0,0 +1,95 @@
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+//
+// Data structures
+//
+
+/// Tracks the state of the runner, so it can be reported by the health and readiness endpoints.
+pub struct Health {
+    state: Mutex<State>,
+}
+
+struct State {
+    last_heartbeat: Instant,
+    last_receive: Option<Instant>,
+    last_receive_error: Option<String>,
+}
+
+/// The result of checking whether the runner loop is alive.
+#[derive(Serialize)]
+pub struct Liveness {
+    pub alive: bool,
+    pub seconds_since_heartbeat: f64,
+}
+
+/// The result of the most recent attempt to receive items from the queue.
+#[derive(Serialize)]
+pub struct QueueStatus {
+    pub ok: bool,
+    pub seconds_since_receive: Option<f64>,
+    pub error: Option<String>,
+}
+
+
+//
+// Functions
+//
+
+impl Default for Health {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Health {
+            state: Mutex::new(State {
+                last_heartbeat: Instant::now(),
+                last_receive: None,
+                last_receive_error: None,
+            }),
+        }
+    }
+
+    /// Record that the runner loop is still making progress.
+    pub fn heartbeat(&self) {
+        self.state.lock().unwrap().last_heartbeat = Instant::now();
+    }
+
+    /// Record the outcome of an attempt to receive items from the queue.
+    pub fn record_receive(&self, error: Option<String>) {
+        let mut state = self.state.lock().unwrap();
+        state.last_heartbeat = Instant::now();
+        state.last_receive = Some(Instant::now());
+        state.last_receive_error = error;
+    }
+
+    /// Check whether the runner loop has made progress within the given time.
+    pub fn liveness(&self, timeout: Duration) -> Liveness {
+        let elapsed = self.state.lock().unwrap().last_heartbeat.elapsed();
+        Liveness {
+            alive: elapsed <= timeout,
+            seconds_since_heartbeat: elapsed.as_secs_f64(),
+        }
+    }
+
+    /// Report whether the most recent attempt to receive items from the queue succeeded.
+    pub fn queue_status(&self) -> QueueStatus {
+        let state = self.state.lock().unwrap();
+        match state.last_receive {
+            None => QueueStatus {
+                ok: false,
+                seconds_since_receive: None,
+                error: Some("no attempt to receive from the queue has completed yet".to_string()),
+            },
+            Some(last_receive) => QueueStatus {
+                ok: state.last_receive_error.is_none(),
+                seconds_since_receive: Some(last_receive.elapsed().as_secs_f64()),
+                error: state.last_receive_error.clone(),
+            },
+        }
+    }
+}
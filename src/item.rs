@@ -1,11 +1,20 @@
+use aws_sdk_sqs::types::MessageAttributeValue;
 use serde_json::Value;
 use std::collections::HashMap;
 
 /// Represents a queue item.
+#[derive(Clone)]
 pub struct Item {
     pub id: String,
+    /// The name of the queue the item was received from
+    pub queue: String,
     pub data: Vec<u8>,
     pub metadata: HashMap<String, String>,
+    /// The item's message attributes, as received (string attributes are also in `metadata`)
+    pub message_attributes: HashMap<String, MessageAttributeValue>,
+    /// Response headers returned by the script during the previous attempt to process this item,
+    /// if it failed
+    pub previous_response_headers: HashMap<String, String>,
 }
 
 impl Item {
@@ -17,11 +26,13 @@ impl Item {
     /// Treat the item's content as a JSON object, and retrieve the value of the given key from the
     /// object (if it's a string)
     pub fn get_string_from_data_json_object(&self, key: &str) -> Option<String> {
-        let json = self.parse_data_as_json();
-        if json.is_err() {
-            log::debug!("[task {}] unable to parse queue item body as JSON: {:?}", self.id, json.unwrap_err());
-            return None;
-        }
-        Some(json.unwrap().get(key)?.as_str()?.to_string())
+        let json = match self.parse_data_as_json() {
+            Ok(json) => json,
+            Err(e) => {
+                log::debug!(task_id = self.id; "unable to parse queue item body as JSON: {:?}", e);
+                return None;
+            }
+        };
+        Some(json.get(key)?.as_str()?.to_string())
     }
 }
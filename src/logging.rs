@@ -0,0 +1,124 @@
+use crate::config::LogFormat;
+use chrono::{SecondsFormat, Utc};
+use log::kv::{self, Key, Value, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use serde_json::{Map, Number};
+use simple_logger::SimpleLogger;
+use std::io::{self, Write};
+
+/// The field which identifies the task a log message relates to.
+const TASK_ID: &str = "task_id";
+
+//
+// Data structures
+//
+
+/// A logger which renders the structured fields attached to log records (e.g.
+/// `log::info!(task_id = item.id; "...")`), either as plain text or as JSON.
+enum Logger {
+    Text(SimpleLogger),
+    Json(LevelFilter),
+}
+
+/// Collects the key-value pairs attached to a log record.
+#[derive(Default)]
+struct Fields(Vec<(String, serde_json::Value)>);
+
+
+//
+// Functions
+//
+
+/// Install the logger.
+pub fn init(level: LevelFilter, format: &LogFormat) -> Result<(), SetLoggerError> {
+    let logger = match format {
+        LogFormat::Text => Logger::Text(SimpleLogger::new().with_level(level)),
+        LogFormat::Json => Logger::Json(level),
+    };
+    log::set_boxed_logger(Box::new(logger))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match self {
+            Logger::Text(inner) => inner.enabled(metadata),
+            Logger::Json(level) => metadata.level() <= *level,
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut fields = Fields::default();
+        _ = record.key_values().visit(&mut fields);
+
+        match self {
+            Logger::Text(inner) => {
+                //Render the task ID as a prefix, and any other fields as a suffix
+                let prefix = match fields.take(TASK_ID) {
+                    Some(serde_json::Value::String(task_id)) => format!("[task {}] ", task_id),
+                    Some(task_id) => format!("[task {}] ", task_id),
+                    None => String::new(),
+                };
+                let suffix: String = fields.0.iter()
+                    .map(|(key, val)| match val {
+                        serde_json::Value::String(str) => format!(" {}={}", key, str),
+                        val => format!(" {}={}", key, val),
+                    })
+                    .collect();
+                inner.log(&Record::builder()
+                    .args(format_args!("{}{}{}", prefix, record.args(), suffix))
+                    .level(record.level())
+                    .target(record.target())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build());
+            },
+            Logger::Json(_) => {
+                let mut object = Map::new();
+                object.insert("timestamp".into(), Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true).into());
+                object.insert("level".into(), record.level().as_str().into());
+                object.insert("target".into(), record.target().into());
+                object.insert("message".into(), record.args().to_string().into());
+                object.extend(fields.0);
+                let mut line = serde_json::Value::Object(object).to_string();
+                line.push('\n');
+                _ = io::stdout().lock().write_all(line.as_bytes());
+            },
+        }
+    }
+
+    fn flush(&self) {
+        _ = io::stdout().flush();
+    }
+}
+
+impl Fields {
+    /// Remove the field with the given key, returning its value.
+    fn take(&mut self, key: &str) -> Option<serde_json::Value> {
+        let index = self.0.iter().position(|(k, _)| k == key)?;
+        Some(self.0.remove(index).1)
+    }
+}
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(b) = value.to_bool() {
+            serde_json::Value::Bool(b)
+        } else if let Some(n) = value.to_u64() {
+            n.into()
+        } else if let Some(n) = value.to_i64() {
+            n.into()
+        } else if let Some(n) = value.to_f64().and_then(Number::from_f64) {
+            serde_json::Value::Number(n)
+        } else {
+            value.to_string().into()
+        };
+        self.0.push((key.to_string(), value));
+        Ok(())
+    }
+}
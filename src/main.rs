@@ -4,73 +4,345 @@ mod queue;
 mod cli;
 mod runner;
 mod item;
+mod decoding;
+mod offload;
+mod metrics;
+mod server;
+mod health;
+mod telemetry;
+mod logging;
+mod audit;
+mod control;
+mod admin;
+mod check;
+mod redrive;
+mod stats;
+mod mapping;
+mod breaker;
+mod ratelimit;
 
-use crate::cli::Args;
+use crate::audit::{AuditLog, Query};
+use crate::breaker::CircuitBreaker;
+use crate::cli::{Args, AuditArgs, CheckArgs, Command, ConfigCommand, ExecArgs, SendArgs};
 use crate::config::Config;
-use crate::pool::Pool;
+use crate::control::Control;
+use crate::health::Health;
+use crate::item::Item;
+use crate::pool::{HttpResponse, Pool};
 use crate::queue::Queue;
-use crate::runner::Runner;
-use anyhow::{anyhow, Context, Error};
-use aws_config::BehaviorVersion;
+use crate::runner::{Runner, TaskConfig};
+use anyhow::{anyhow, bail, Context, Error};
 use clap::Parser;
 use log::LevelFilter;
-use simple_logger::SimpleLogger;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
 use std::str::FromStr;
 use std::sync::Arc;
-use tokio::signal;
+use std::time::{Duration, SystemTime};
+use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::interval;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+/// How often to check whether the configuration file has changed.
+const CONFIG_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     //Parse configuration
     let args = Args::parse();
-    let config = Config::from_file(&args.config)
-        .context("Configuration file error")?;
+    let source = config::Source {
+        path: args.config_path(),
+        format: args.config_format,
+        overrides: args.overrides.values(),
+    };
+    let load_config = || source.load()
+        .context("Configuration file error");
+
+    match args.command {
+        None => run(load_config()?, &source).await,
+        Some(Command::Audit(audit_args)) => search_audit_log(load_config()?, audit_args),
+        Some(Command::Send(send_args)) => send_message(load_config()?, send_args).await,
+        Some(Command::Exec(exec_args)) => exec_payload(load_config()?, exec_args).await,
+        Some(Command::Check(check_args)) => run_checks(&source, &check_args).await,
+        Some(Command::Redrive(redrive_args)) => redrive::run(load_config()?, redrive_args).await,
+        Some(Command::Stats(stats_args)) => stats::run(load_config()?, stats_args).await,
+        Some(Command::Config(ConfigCommand::Schema)) => {
+            println!("{}", serde_json::to_string_pretty(&Config::json_schema())?);
+            Ok(())
+        },
+    }
+}
+
+/// Process items from the queue until a termination signal is received.
+async fn run(mut config: Config, source: &config::Source) -> Result<(), Error> {
+    let mut config_modified = source.path.as_deref().and_then(modified_time);
     let log_level = LevelFilter::from_str(&config.log_level)
         .context("Unrecognized value for log_Level in configuration file")?;
-    let mut aws_config = aws_config::defaults(BehaviorVersion::v2024_03_28());
-    if !config.queue.sqs.api_endpoint_url.is_empty() {
-        aws_config = aws_config.endpoint_url(&config.queue.sqs.api_endpoint_url);
-    }
 
     //Initialize components
-    SimpleLogger::new().with_level(log_level).init()?;
-    let queue = Arc::new(
-        Queue::new(
-            config.queue.sqs.queue_url.clone(),
-            config.queue.sqs.visibility_timeout,
-            &aws_config.load().await,
-        )
-    );
+    logging::init(log_level, &config.log_format)?;
+    let tracer_provider = telemetry::init(config.tracing.as_ref())
+        .context("Unable to initialize tracing")?;
+    let queue = Arc::new(Queue::from_config(&config.queue).await);
+    let audit_log = config.audit.as_ref()
+        .map(AuditLog::open)
+        .transpose()
+        .context("Unable to open audit log")?;
     let pool = Arc::new(
         Pool::new(
             config.fastcgi.address.clone(),
             config.fastcgi.port,
             config.fastcgi.script_path.clone(),
-            config.fastcgi.cgi_environment.clone(),
         )
     );
 
-    //Start runner
+    //Start listeners, before any tasks are received, so that we can exit if they can't be started
     log::info!("fcgiq v{} is starting", VERSION);
+    let health = Arc::new(Health::new());
+    if let Some(http_config) = config.http.clone() {
+        let listener = server::bind(&http_config).await
+            .context("Unable to start HTTP listener")?;
+        let health = Arc::clone(&health);
+        let pool = Arc::clone(&pool);
+        tokio::spawn(async move {
+            if let Err(e) = server::serve(listener, http_config, health, pool).await {
+                log::error!("HTTP listener failed: {:#}", anyhow!(e));
+            }
+        });
+    }
+    let control = Arc::new(Control::new(config.fastcgi.max_parallel_requests as usize));
+    if let Some(admin_config) = &config.admin {
+        let listener = admin::bind(admin_config).await
+            .context("Unable to start admin API listener")?;
+        let control = Arc::clone(&control);
+        tokio::spawn(async move {
+            if let Err(e) = admin::serve(listener, control).await {
+                log::error!("Admin API listener failed: {:#}", anyhow!(e));
+            }
+        });
+    }
+
+    //Start runner
     let runner = Runner::start(
-        config.fastcgi.max_parallel_requests as usize,
         Arc::clone(&pool),
         Arc::clone(&queue),
-        config.field_mappings.clone(),
+        TaskConfig::from_config(&config)?,
+        CircuitBreaker::new(config.fastcgi.circuit_breaker.clone()),
+        Arc::clone(&health),
+        audit_log,
+        Arc::clone(&control),
     );
     log::info!("Listening on queue {}", &config.queue.sqs.queue_url);
 
-    //Wait for termination signal
-    match signal::ctrl_c().await {
-        Ok(()) => {
-            log::info!("Received shutdown request.");
+    //Handle signals until we receive a termination signal, or a drain request from the admin API
+    let mut sigint = signal(SignalKind::interrupt()).context("Unable to listen for SIGINT")?;
+    let mut sigterm = signal(SignalKind::terminate()).context("Unable to listen for SIGTERM")?;
+    let mut sighup = signal(SignalKind::hangup()).context("Unable to listen for SIGHUP")?;
+    let mut sigusr1 = signal(SignalKind::user_defined1()).context("Unable to listen for SIGUSR1")?;
+    let mut config_check = interval(CONFIG_CHECK_INTERVAL);
+    loop {
+        select! {
+            _ = sigint.recv() => {
+                log::info!("Received SIGINT; shutting down.");
+                break;
+            },
+            _ = sigterm.recv() => {
+                log::info!("Received SIGTERM; shutting down.");
+                break;
+            },
+            _ = control.drain_requested() => {
+                log::info!("Received drain request.");
+                break;
+            },
+            _ = sighup.recv() => {
+                log::info!("Received SIGHUP; reloading configuration from {}", source);
+                reload_config(source, &mut config, &runner, &control);
+            },
+            _ = config_check.tick() => {
+                let modified = source.path.as_deref().and_then(modified_time);
+                if modified != config_modified {
+                    config_modified = modified;
+                    log::info!("Configuration file {} changed; reloading", source);
+                    reload_config(source, &mut config, &runner, &control);
+                }
+            },
+            _ = sigusr1.recv() => log_state(&control, &health),
+        }
+    }
+    control.request_drain();
+    runner.stop(Duration::from_secs(config.drain_timeout)).await;
+    telemetry::shutdown(tracer_provider);
+    Ok(())
+}
+
+/// Re-read the configuration file, and apply the settings which can be changed while running.
+/// Tasks already in flight keep the settings they started with. If the file is invalid, the
+/// current configuration is kept.
+fn reload_config(source: &config::Source, config: &mut Config, runner: &Runner, control: &Control) {
+    let new_config = match source.load() {
+        Ok(new_config) => new_config,
+        Err(e) => {
+            log::error!("{:#}", anyhow!(e).context("Configuration file error; keeping the current configuration"));
+            return;
         },
-        Err(err) => {
-            eprintln!("Unable to listen for shutdown signal: {:#}", anyhow!(err));
+    };
+    if new_config == *config {
+        log::info!("Configuration is unchanged");
+        return;
+    }
+
+    let task_config = match TaskConfig::from_config(&new_config) {
+        Ok(task_config) => task_config,
+        Err(e) => {
+            log::error!("{:#}", anyhow!(e).context("Configuration file error; keeping the current configuration"));
+            return;
         },
+    };
+
+    runner.reconfigure(task_config);
+    if new_config.fastcgi.max_parallel_requests != config.fastcgi.max_parallel_requests {
+        //Only override a limit set through the admin API if the configured limit has changed
+        control.set_max_tasks(new_config.fastcgi.max_parallel_requests as usize);
+    }
+
+    //Warn about any changes which can't be applied without a restart
+    let mut reloadable = config.clone();
+    reloadable.fastcgi.cgi_environment = new_config.fastcgi.cgi_environment.clone();
+    reloadable.fastcgi.max_parallel_requests = new_config.fastcgi.max_parallel_requests;
+    reloadable.body_decoding = new_config.body_decoding.clone();
+    reloadable.max_decoded_bytes = new_config.max_decoded_bytes;
+    reloadable.field_mappings = new_config.field_mappings.clone();
+    reloadable.concurrency_key = new_config.concurrency_key.clone();
+    reloadable.rate_limit = new_config.rate_limit.clone();
+    reloadable.drain_timeout = new_config.drain_timeout;
+    if reloadable != new_config {
+        log::warn!("Some configuration changes will only take effect when fcgiq is restarted");
+    }
+    *config = reloadable;
+    log::info!("Configuration reloaded");
+}
+
+/// The time the configuration file was last modified, if it can be determined.
+fn modified_time(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Write a summary of the runner's state to the log.
+fn log_state(control: &Control, health: &Health) {
+    let settings = control.settings();
+    let in_flight = control.in_flight();
+    let queue_status = health.queue_status();
+    let recent = control.recent_outcomes();
+    log::info!(
+        paused = settings.paused, draining = control.is_draining(), max_tasks = settings.max_tasks,
+        in_flight = in_flight.len(), recent_succeeded = recent.succeeded, recent_failed = recent.failed,
+        queue_ok = queue_status.ok,
+        queue_error = queue_status.error.unwrap_or_default();
+        "Received SIGUSR1; runner state follows"
+    );
+    for task in in_flight {
+        log::info!(task_id = task.id, queue = task.queue, elapsed_seconds = task.elapsed_seconds; "task in flight");
+    }
+}
+
+/// Print the audit records matching the given criteria, one JSON object per line.
+fn search_audit_log(config: Config, args: AuditArgs) -> Result<(), Error> {
+    let audit_config = config.audit
+        .ok_or_else(|| anyhow!("No audit log is configured"))?;
+    let audit_log = AuditLog::open_read_only(&audit_config)
+        .context("Unable to open audit log")?;
+    let records = audit_log.search(&Query {
+        message_id: args.id,
+        since: args.since,
+        until: args.until,
+        limit: args.limit,
+    })?;
+    let mut stdout = io::stdout().lock();
+    for record in records {
+        serde_json::to_writer(&mut stdout, &record)?;
+        stdout.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Add a message to the configured queue, and print its ID.
+async fn send_message(config: Config, args: SendArgs) -> Result<(), Error> {
+    let body = String::from_utf8(read_body(args.body, args.file)?)
+        .context("Message body is not valid UTF-8")?;
+    let queue = Queue::from_config(&config.queue).await;
+    let message_id = queue.send(
+        body,
+        args.attributes.into_iter().collect(),
+        args.group_id,
+        args.deduplication_id,
+        args.delay,
+    ).await?;
+    println!("{}", message_id);
+    Ok(())
+}
+
+/// Dispatch a single payload to the FastCGI server, as if it had been received from the queue,
+/// and print the response. Fails if the script doesn't return a successful status code.
+async fn exec_payload(config: Config, args: ExecArgs) -> Result<(), Error> {
+    let mut item = Item {
+        id: args.id,
+        queue: queue::name_from_url(&config.queue.sqs.queue_url).to_string(),
+        data: read_body(args.body, args.file)?,
+        metadata: args.metadata.into_iter().collect(),
+        message_attributes: HashMap::new(),
+        previous_response_headers: HashMap::new(),
+    };
+    decoding::decode(&mut item, &config.body_decoding, config.max_decoded_bytes)?;
+    let env = mapping::map_fields(&item, &mapping::compile(&config.field_mappings)?)?;
+    let pool = Pool::new(
+        config.fastcgi.address,
+        config.fastcgi.port,
+        config.fastcgi.script_path,
+    );
+    let output = pool.dispatch(&item.data, &config.fastcgi.cgi_environment, env).await?;
+    io::stderr().write_all(&output.stderr)?;
+    let response: HttpResponse = output.try_into()?;
+
+    let mut stdout = io::stdout().lock();
+    writeln!(stdout, "{:?} {}", response.version(), response.status())?;
+    for (name, value) in response.headers() {
+        writeln!(stdout, "{}: {}", name, String::from_utf8_lossy(value.as_bytes()))?;
+    }
+    writeln!(stdout)?;
+    stdout.write_all(response.body())?;
+    stdout.flush()?;
+
+    if !response.status().is_success() {
+        bail!("script returned status code {}", response.status());
+    }
+    Ok(())
+}
+
+/// Read a message body from the command line argument if given, otherwise from the given file,
+/// otherwise from stdin.
+fn read_body(body: Option<String>, file: Option<String>) -> Result<Vec<u8>, Error> {
+    match (body, file) {
+        (Some(body), _) => Ok(body.into_bytes()),
+        (None, Some(path)) => fs::read(&path)
+            .with_context(|| format!("Unable to read message body from {}", path)),
+        (None, None) => {
+            let mut body = Vec::new();
+            io::stdin().read_to_end(&mut body)
+                .context("Unable to read message body from stdin")?;
+            Ok(body)
+        },
+    }
+}
+
+/// Run the preflight checks and print a report. Fails if any check failed.
+async fn run_checks(source: &config::Source, args: &CheckArgs) -> Result<(), Error> {
+    let results = check::run(source, args).await;
+    check::print_report(&results);
+    if results.iter().any(|result| result.outcome == check::Outcome::Fail) {
+        bail!("preflight checks failed");
     }
-    runner.stop().await;
     Ok(())
 }
@@ -0,0 +1,348 @@
+use crate::config::{FieldMapping, FieldMappings, FieldSource, Transform};
+use crate::item::Item;
+use base64::prelude::*;
+use chrono::format::StrftimeItems;
+use chrono::Utc;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use regex::Regex;
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Write;
+use std::result;
+use thiserror::Error;
+
+//
+// Data structures
+//
+
+/// Field mappings, keyed by CGI environment variable name, prepared to be applied to queue items.
+pub type Mappings = Vec<(String, Mapping)>;
+
+/// A field mapping prepared to be applied to queue items, with its template parsed and its
+/// regular expressions compiled.
+pub struct Mapping {
+    config: FieldMapping,
+    template: Option<Template>,
+    transforms: Vec<CompiledTransform>,
+}
+
+/// A transformation, along with its compiled regular expression (if it has one).
+struct CompiledTransform {
+    transform: Transform,
+    regex: Option<Regex>,
+}
+
+/// A parsed `template` string from a field mapping, e.g. `/jobs/{body.type}/{meta.traceId}`.
+pub struct Template {
+    parts: Vec<TemplatePart>,
+}
+
+/// Characters which are percent-encoded by the `UrlEncode` transform: everything except the
+/// unreserved characters from RFC 3986.
+const URL_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.').remove(b'~');
+
+enum TemplatePart {
+    Literal(String),
+    Placeholder(FieldSource, String),
+}
+
+
+//
+// Functions
+//
+
+/// Prepare the configured field mappings to be applied to queue items.
+pub fn compile(mappings: &FieldMappings) -> Result<Mappings> {
+    mappings.iter()
+        .map(|(key, field_mapping)| Ok((key.clone(), Mapping::new(field_mapping)?)))
+        .collect()
+}
+
+/// Build the set of CGI environment overrides for a queue item, according to the configured
+/// field mappings.
+pub fn map_fields(item: &Item, mappings: &Mappings) -> Result<HashMap<String, String>> {
+    let mut env = HashMap::new();
+    for (key, mapping) in mappings.iter() {
+        if let Some(val) = mapping.map(item) {
+            log::debug!(task_id = item.id; "env override: {}={}", key, &val);
+            env.insert(key.to_owned(), val);
+        } else if mapping.config.required {
+            return Err(Error::MissingRequiredField(key.to_owned()));
+        }
+    }
+    Ok(env)
+}
+
+impl Mapping {
+    /// Prepare a field mapping, checking that its template and its transformations' parameters
+    /// are valid.
+    pub fn new(config: &FieldMapping) -> Result<Self> {
+        let template = config.template.as_deref().map(Template::parse).transpose()?;
+        let transforms = config.transforms.iter()
+            .map(CompiledTransform::new)
+            .collect::<Result<_>>()?;
+        Ok(Mapping { config: config.clone(), template, transforms })
+    }
+
+    /// Determine the value of the field mapping for a queue item, falling back to the mapping's
+    /// default value if the source doesn't provide one.
+    fn map(&self, item: &Item) -> Option<String> {
+        let mut val = if let Some(template) = &self.template {
+            template.render(item)
+        } else if let Some(source) = &self.config.source {
+            resolve(item, source, &self.config.field)
+        } else {
+            None
+        };
+        for transform in self.transforms.iter() {
+            let Some(input) = val else { break };
+            val = transform.apply(&input);
+            if val.is_none() {
+                log::debug!(task_id = item.id; "transform {:?} discarded value {}", transform.transform, input);
+            }
+        }
+        val.or_else(|| self.config.default.clone())
+    }
+}
+
+/// Retrieve the value of a field from the given source.
+pub fn resolve(item: &Item, source: &FieldSource, field: &str) -> Option<String> {
+    match source {
+        FieldSource::BodyJson => item.get_string_from_data_json_object(field),
+        FieldSource::Metadata => item.metadata.get(field).cloned(),
+        FieldSource::ItemId => Some(item.id.clone()),
+        FieldSource::QueueName => Some(item.queue.clone()),
+        FieldSource::ReceiveCount => item.metadata.get("ApproximateReceiveCount").cloned(),
+        FieldSource::Env => env::var(field).ok(),
+        FieldSource::Header => item.previous_response_headers.get(&field.to_ascii_lowercase()).cloned(),
+        FieldSource::Now => {
+            let now = Utc::now();
+            if field.is_empty() {
+                Some(now.to_rfc3339())
+            } else {
+                let mut formatted = String::new();
+                write!(formatted, "{}", now.format(field)).ok()?;
+                Some(formatted)
+            }
+        },
+        FieldSource::Static => Some(field.to_string()),
+    }
+}
+
+impl Template {
+    /// Parse a template string. Placeholders take the form `{source.field}`, where source is one
+    /// of `body`, `meta`, `env` or `header`, or `{source}` where source is one of `id`, `queue`,
+    /// `receive_count` or `now` (which also accepts an optional format, e.g. `{now.%Y-%m-%d}`).
+    /// A literal brace can be written as `{{` or `}}`.
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                },
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                },
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => placeholder.push(c),
+                            None => return Err(Error::InvalidTemplate(
+                                template.to_string(), "unterminated placeholder".to_string()
+                            )),
+                        }
+                    }
+                    if !literal.is_empty() {
+                        parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(parse_placeholder(template, &placeholder)?);
+                },
+                '}' => return Err(Error::InvalidTemplate(
+                    template.to_string(), "unmatched '}'".to_string()
+                )),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(literal));
+        }
+        Ok(Template { parts })
+    }
+
+    /// Substitute values from the queue item into the template. If any placeholder has no value,
+    /// the result is `None`.
+    pub fn render(&self, item: &Item) -> Option<String> {
+        let mut output = String::new();
+        for part in self.parts.iter() {
+            match part {
+                TemplatePart::Literal(str) => output.push_str(str),
+                TemplatePart::Placeholder(source, field) => output.push_str(&resolve(item, source, field)?),
+            }
+        }
+        Some(output)
+    }
+}
+
+impl CompiledTransform {
+    /// Compile the transformation's regular expression (if it has one), checking that its
+    /// parameters are valid.
+    fn new(transform: &Transform) -> Result<Self> {
+        let regex = match transform {
+            Transform::RegexExtract { pattern, group } => {
+                let regex = Regex::new(pattern)
+                    .map_err(|e| Error::InvalidTransform(e.to_string()))?;
+                if *group >= regex.captures_len() {
+                    return Err(Error::InvalidTransform(
+                        format!("regular expression '{}' has no capture group {}", pattern, group)
+                    ));
+                }
+                Some(regex)
+            },
+            _ => None,
+        };
+        Ok(CompiledTransform { transform: transform.clone(), regex })
+    }
+
+    /// Apply the transformation to a value. Returns `None` if the value can't be transformed (e.g.
+    /// a regular expression doesn't match, or the value isn't valid base64).
+    fn apply(&self, val: &str) -> Option<String> {
+        match &self.transform {
+            Transform::Uppercase => Some(val.to_uppercase()),
+            Transform::Lowercase => Some(val.to_lowercase()),
+            Transform::Trim => Some(val.trim().to_string()),
+            Transform::RegexExtract { group, .. } => self.regex.as_ref()?
+                .captures(val)
+                .and_then(|captures| captures.get(*group))
+                .map(|m| m.as_str().to_string()),
+            Transform::Base64Encode => Some(BASE64_STANDARD.encode(val)),
+            Transform::Base64Decode => BASE64_STANDARD.decode(val).ok()
+                .and_then(|bytes| String::from_utf8(bytes).ok()),
+            Transform::UrlEncode => Some(utf8_percent_encode(val, URL_ENCODE_SET).to_string()),
+            Transform::UrlDecode => percent_decode_str(val).decode_utf8().ok()
+                .map(|decoded| decoded.into_owned()),
+        }
+    }
+}
+
+/// Check that a strftime-style format string (as used by the `Now` source) is valid.
+pub fn is_valid_time_format(format: &str) -> bool {
+    !StrftimeItems::new(format).any(|item| item == chrono::format::Item::Error)
+}
+
+fn parse_placeholder(template: &str, placeholder: &str) -> Result<TemplatePart> {
+    let invalid = |reason: String| Error::InvalidTemplate(template.to_string(), reason);
+    let (prefix, field) = placeholder.split_once('.').unwrap_or((placeholder, ""));
+    let source = match prefix {
+        "body" => FieldSource::BodyJson,
+        "meta" => FieldSource::Metadata,
+        "id" => FieldSource::ItemId,
+        "queue" => FieldSource::QueueName,
+        "receive_count" => FieldSource::ReceiveCount,
+        "env" => FieldSource::Env,
+        "header" => FieldSource::Header,
+        "now" => FieldSource::Now,
+        _ => return Err(invalid(format!("unknown placeholder source '{}'", prefix))),
+    };
+    if source.requires_field() && field.is_empty() {
+        return Err(invalid(format!("placeholder {{{}}} must take the form {{{}.field}}", placeholder, prefix)));
+    }
+    if !source.requires_field() && source != FieldSource::Now && !field.is_empty() {
+        return Err(invalid(format!("placeholder {{{}}} does not accept a field", placeholder)));
+    }
+    if source == FieldSource::Now && !is_valid_time_format(field) {
+        return Err(invalid(format!("invalid time format '{}'", field)));
+    }
+    Ok(TemplatePart::Placeholder(source, field.to_string()))
+}
+
+
+//
+// Error handling
+//
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("no value available for required field mapping {0}")]
+    MissingRequiredField(String),
+
+    #[error("invalid template \"{0}\": {1}")]
+    InvalidTemplate(String, String),
+
+    #[error("invalid transform: {0}")]
+    InvalidTransform(String),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item() -> Item {
+        Item {
+            id: "msg-1".to_string(),
+            queue: "jobs".to_string(),
+            data: br#"{"type":"email"}"#.to_vec(),
+            metadata: HashMap::from([("traceId".to_string(), "abc".to_string())]),
+            message_attributes: HashMap::new(),
+            previous_response_headers: HashMap::new(),
+        }
+    }
+
+    fn render(template: &str) -> Option<String> {
+        Template::parse(template).unwrap().render(&item())
+    }
+
+    fn parse_error(template: &str) -> String {
+        match Template::parse(template) {
+            Err(Error::InvalidTemplate(_, reason)) => reason,
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("template {} should be invalid", template),
+        }
+    }
+
+    #[test]
+    fn template_substitutes_placeholders() {
+        assert_eq!(render("/jobs/{body.type}/{meta.traceId}"), Some("/jobs/email/abc".to_string()));
+        assert_eq!(render("{id}@{queue}"), Some("msg-1@jobs".to_string()));
+        assert_eq!(render("no placeholders"), Some("no placeholders".to_string()));
+    }
+
+    #[test]
+    fn template_without_a_value_renders_nothing() {
+        assert_eq!(render("/jobs/{body.missing}"), None);
+    }
+
+    #[test]
+    fn template_escapes_braces() {
+        assert_eq!(render("{{literal}}"), Some("{literal}".to_string()));
+        assert_eq!(render("{{{body.type}}}"), Some("{email}".to_string()));
+        assert_eq!(render("a}}b{{c"), Some("a}b{c".to_string()));
+    }
+
+    #[test]
+    fn template_rejects_unterminated_placeholder() {
+        assert_eq!(parse_error("/jobs/{body.type"), "unterminated placeholder");
+        assert_eq!(parse_error("{"), "unterminated placeholder");
+    }
+
+    #[test]
+    fn template_rejects_unmatched_closing_brace() {
+        assert_eq!(parse_error("/jobs/}"), "unmatched '}'");
+    }
+
+    #[test]
+    fn template_rejects_invalid_placeholders() {
+        assert_eq!(parse_error("{nope.field}"), "unknown placeholder source 'nope'");
+        assert_eq!(parse_error("{body}"), "placeholder {body} must take the form {body.field}");
+        assert_eq!(parse_error("{id.field}"), "placeholder {id.field} does not accept a field");
+    }
+}
@@ -0,0 +1,92 @@
+use prometheus::{
+    register_histogram, register_int_counter, register_int_counter_vec, register_int_gauge, Encoder, Histogram,
+    IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+use std::sync::LazyLock;
+
+//
+// Metrics
+//
+
+pub static TASKS_RECEIVED: LazyLock<IntCounter> = LazyLock::new(|| register_int_counter!(
+    "fcgiq_tasks_received_total",
+    "Number of items received from the queue"
+).unwrap());
+
+pub static TASKS_SUCCEEDED: LazyLock<IntCounter> = LazyLock::new(|| register_int_counter!(
+    "fcgiq_tasks_succeeded_total",
+    "Number of tasks which completed successfully"
+).unwrap());
+
+pub static TASKS_FAILED: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "fcgiq_tasks_failed_total",
+    "Number of tasks which failed, by reason and HTTP status code (if the script returned a response)",
+    &["reason", "status"]
+).unwrap());
+
+pub static TASKS_DEFERRED: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "fcgiq_tasks_deferred_total",
+    "Number of items returned to the queue unprocessed, because of a concurrency or rate limit or an open circuit breaker, by reason",
+    &["reason"]
+).unwrap());
+
+pub static DISPATCH_DURATION: LazyLock<Histogram> = LazyLock::new(|| register_histogram!(
+    "fcgiq_dispatch_duration_seconds",
+    "Time taken for the FastCGI pool to execute a task",
+    vec![0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0]
+).unwrap());
+
+pub static BUSY_SLOTS: LazyLock<IntGauge> = LazyLock::new(|| register_int_gauge!(
+    "fcgiq_busy_slots",
+    "Number of tasks currently being executed"
+).unwrap());
+
+pub static MAX_SLOTS: LazyLock<IntGauge> = LazyLock::new(|| register_int_gauge!(
+    "fcgiq_max_slots",
+    "Maximum number of tasks which may be executed simultaneously"
+).unwrap());
+
+pub static CIRCUIT_BREAKER_OPEN: LazyLock<IntGauge> = LazyLock::new(|| register_int_gauge!(
+    "fcgiq_circuit_breaker_open",
+    "Whether receiving tasks is suspended because FastCGI isn't accepting connections (1) or not (0)"
+).unwrap());
+
+pub static SQS_RECEIVE_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "fcgiq_sqs_receive_errors_total",
+    "Number of errors receiving items from the queue, by error type",
+    &["error"]
+).unwrap());
+
+pub static SQS_DELETE_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "fcgiq_sqs_delete_errors_total",
+    "Number of errors removing completed items from the queue, by error type",
+    &["error"]
+).unwrap());
+
+
+//
+// Functions
+//
+
+/// Ensure all metrics are registered, so they are reported even before they are first updated.
+pub fn register() {
+    LazyLock::force(&TASKS_RECEIVED);
+    LazyLock::force(&TASKS_SUCCEEDED);
+    LazyLock::force(&TASKS_FAILED);
+    LazyLock::force(&TASKS_DEFERRED);
+    LazyLock::force(&DISPATCH_DURATION);
+    LazyLock::force(&BUSY_SLOTS);
+    LazyLock::force(&MAX_SLOTS);
+    LazyLock::force(&CIRCUIT_BREAKER_OPEN);
+    LazyLock::force(&SQS_RECEIVE_ERRORS);
+    LazyLock::force(&SQS_DELETE_ERRORS);
+}
+
+/// Render all registered metrics in the Prometheus text exposition format.
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        log::error!("Unable to encode metrics: {}", e);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
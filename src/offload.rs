@@ -0,0 +1,158 @@
+use crate::config;
+use crate::item::Item;
+use aws_config::SdkConfig;
+use aws_sdk_s3::error::SdkError;
+use aws_sdk_s3::operation::delete_object::DeleteObjectError;
+use aws_sdk_s3::operation::get_object::GetObjectError;
+use aws_sdk_s3::primitives::ByteStreamError;
+use aws_sdk_s3::Client;
+use serde::Deserialize;
+use serde_json::Value;
+use std::result;
+use thiserror::Error;
+
+/// The class name which the SQS Extended Client Library uses to identify a message body that
+/// points to a payload stored in S3.
+const POINTER_CLASS: &str = "software.amazon.payloadoffloading.PayloadS3Pointer";
+
+/// Item metadata keys under which the location of an offloaded payload is recorded.
+const BUCKET_METADATA_KEY: &str = "payload_s3_bucket";
+const KEY_METADATA_KEY: &str = "payload_s3_key";
+
+//
+// Data structures
+//
+
+/// Abstraction for S3-compatible object storage holding queue item payloads which were too large
+/// to send through the queue itself (the "extended client" pattern).
+pub struct PayloadStore {
+    client: Client,
+    delete_after_acknowledge: bool,
+    max_bytes: u64,
+}
+
+/// The location of an offloaded payload, as encoded in a message body by the extended client.
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PayloadPointer {
+    pub s3_bucket_name: String,
+    pub s3_key: String,
+}
+
+
+//
+// Functions
+//
+
+impl PayloadStore {
+    pub fn new(config: &config::S3, sdk_config: &SdkConfig) -> Self {
+        let endpoint_url = Some(config.api_endpoint_url.clone())
+            .filter(|url| !url.is_empty());
+        let mut s3_config = aws_sdk_s3::config::Builder::from(sdk_config)
+            .force_path_style(config.force_path_style);
+        s3_config.set_endpoint_url(endpoint_url);
+        PayloadStore {
+            client: Client::from_conf(s3_config.build()),
+            delete_after_acknowledge: config.delete_after_acknowledge,
+            max_bytes: config.max_bytes,
+        }
+    }
+
+    /// If the item's body is a pointer to an offloaded payload, replace the body with the payload.
+    /// Fails if the payload is larger than the configured maximum.
+    pub async fn fetch(&self, item: &mut Item) -> Result<()> {
+        let Some(pointer) = PayloadPointer::parse(&item.data) else {
+            return Ok(());
+        };
+        log::debug!(task_id = item.id; "fetching payload from s3://{}/{}", pointer.s3_bucket_name, pointer.s3_key);
+
+        let output = self.client.get_object()
+            .bucket(&pointer.s3_bucket_name)
+            .key(&pointer.s3_key)
+            .send().await?;
+        if output.content_length.is_some_and(|length| length as u64 > self.max_bytes) {
+            return Err(Error::TooLarge(self.max_bytes));
+        }
+        //The content length isn't always given, so count the bytes as they arrive too
+        let mut body = output.body;
+        let mut data = Vec::new();
+        while let Some(chunk) = body.try_next().await? {
+            if (data.len() + chunk.len()) as u64 > self.max_bytes {
+                return Err(Error::TooLarge(self.max_bytes));
+            }
+            data.extend_from_slice(&chunk);
+        }
+        item.data = data;
+        item.metadata.insert(BUCKET_METADATA_KEY.to_string(), pointer.s3_bucket_name);
+        item.metadata.insert(KEY_METADATA_KEY.to_string(), pointer.s3_key);
+        Ok(())
+    }
+
+    /// Delete the offloaded payload belonging to an item which has been acknowledged, if
+    /// configured to do so.
+    pub async fn delete(&self, item: &Item) -> Result<()> {
+        if !self.delete_after_acknowledge {
+            return Ok(());
+        }
+        let (Some(bucket), Some(key)) = (item.metadata.get(BUCKET_METADATA_KEY), item.metadata.get(KEY_METADATA_KEY)) else {
+            return Ok(());
+        };
+        log::debug!(task_id = item.id; "deleting payload s3://{}/{}", bucket, key);
+
+        self.client.delete_object()
+            .bucket(bucket)
+            .key(key)
+            .send().await?;
+        Ok(())
+    }
+}
+
+impl PayloadPointer {
+    /// Attempt to interpret a message body as a payload pointer, which takes the form
+    /// `["software.amazon.payloadoffloading.PayloadS3Pointer", {"s3BucketName": ..., "s3Key": ...}]`
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if !data.starts_with(b"[") {
+            return None;
+        }
+        let json: Value = serde_json::from_slice(data).ok()?;
+        let [class, pointer] = json.as_array()?.as_slice() else {
+            return None;
+        };
+        if class.as_str()? != POINTER_CLASS {
+            return None;
+        }
+        serde_json::from_value(pointer.clone()).ok()
+    }
+}
+
+
+//
+// Error handling
+//
+
+#[derive(Debug, Error)]
+#[allow(clippy::enum_variant_names)]
+pub enum Error {
+    #[error("S3 GetObject API call failed")]
+    S3GetObjectError(#[from] GetObjectError),
+    #[error("S3 DeleteObject API call failed")]
+    S3DeleteObjectError(#[from] DeleteObjectError),
+    #[error("failed to read object from S3")]
+    S3ByteStreamError(#[from] ByteStreamError),
+    #[error("offloaded payload is larger than {0} bytes")]
+    TooLarge(u64),
+}
+
+impl From<SdkError<GetObjectError>> for Error {
+    fn from(value: SdkError<GetObjectError>) -> Self {
+        Error::S3GetObjectError(value.into_service_error())
+    }
+}
+
+impl From<SdkError<DeleteObjectError>> for Error {
+    fn from(value: SdkError<DeleteObjectError>) -> Self {
+        Error::S3DeleteObjectError(value.into_service_error())
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
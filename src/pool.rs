@@ -13,7 +13,6 @@ pub struct Pool {
     address: String,
     port: u16,
     script_path: String,
-    cgi_environment: HashMap<String, String>,
 }
 
 /// Holds the output from an execution of a FastCGI script.
@@ -30,15 +29,33 @@ pub type HttpResponse = http::Response<Vec<u8>>;
 //
 
 impl Pool {
-    pub fn new(address: String, port: u16, script_path: String, cgi_environment: HashMap<String, String>) -> Pool {
-        Pool { address, port, script_path, cgi_environment }
+    pub fn new(address: String, port: u16, script_path: String) -> Pool {
+        Pool { address, port, script_path }
+    }
+
+    /// The script executed to handle each task.
+    pub fn script_path(&self) -> &str {
+        &self.script_path
     }
 
     async fn connect(&self) -> Result<TcpStream> {
         Ok(TcpStream::connect((self.address.clone(), self.port)).await?)
     }
 
-    pub async fn dispatch(&self, stdin: &[u8], environment_overrides: HashMap<String, String>) -> Result<ScriptOutput> {
+    /// Check that the FastCGI server is accepting connections.
+    pub async fn check_connection(&self) -> Result<()> {
+        self.connect().await?;
+        Ok(())
+    }
+
+    /// Execute the script, with the CGI environment fields from the config file and then those
+    /// from the task request overriding the defaults.
+    pub async fn dispatch(
+        &self,
+        stdin: &[u8],
+        cgi_environment: &HashMap<String, String>,
+        environment_overrides: HashMap<String, String>,
+    ) -> Result<ScriptOutput> {
         let client = Client::new(self.connect().await?);
 
         //Set fallback defaults for essential CGI environment fields
@@ -54,7 +71,7 @@ impl Pool {
             .server_software("fcgiq");
 
         //Override CGI environment fields from the config file
-        for (key, val) in self.cgi_environment.iter() {
+        for (key, val) in cgi_environment.iter() {
             params.insert(key.into(), val.into());
         }
 
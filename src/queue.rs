@@ -1,31 +1,173 @@
+use crate::config;
 use crate::item::Item;
-use aws_config::SdkConfig;
-use aws_sdk_sqs::error::SdkError;
+use crate::offload::{self, PayloadStore};
+use aws_config::{BehaviorVersion, SdkConfig};
+use aws_sdk_cloudwatch::operation::get_metric_statistics::GetMetricStatisticsError;
+use aws_sdk_cloudwatch::primitives::DateTime;
+use aws_sdk_cloudwatch::types::{Dimension, Statistic};
+use aws_sdk_sqs::error::{BuildError, SdkError};
+use aws_sdk_sqs::operation::change_message_visibility::ChangeMessageVisibilityError;
 use aws_sdk_sqs::operation::delete_message::DeleteMessageError;
+use aws_sdk_sqs::operation::get_queue_attributes::GetQueueAttributesError;
 use aws_sdk_sqs::operation::receive_message::ReceiveMessageError;
-use aws_sdk_sqs::types::{Message, MessageSystemAttributeName};
+use aws_sdk_sqs::operation::send_message::SendMessageError;
+use aws_sdk_sqs::types::{Message, MessageAttributeValue, MessageSystemAttributeName, QueueAttributeName};
 use aws_sdk_sqs::Client;
+use chrono::Utc;
 use std::collections::HashMap;
 use std::result;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use thiserror::Error;
 
+/// How far back to look for the most recent datapoint of a CloudWatch metric.
+const METRIC_LOOKBACK: Duration = Duration::from_secs(15 * 60);
+
 /// Abstraction for a remote SQS queue.
 pub struct Queue {
     queue_url: String,
     visibility_timeout: i32,
     client: Client,
+    cloudwatch_client: aws_sdk_cloudwatch::Client,
+    payload_store: Option<PayloadStore>,
+}
+
+/// The approximate number of messages in a queue, by state.
+pub struct QueueStats {
+    /// Messages available for retrieval
+    pub visible: u64,
+    /// Messages which have been received, but not yet deleted or released
+    pub not_visible: u64,
+    /// Messages which are delayed, and not yet available for retrieval
+    pub delayed: u64,
 }
 
 impl Queue {
-    pub fn new(queue_url: String, visibility_timeout: i32, sdk_config: &SdkConfig) -> Self {
+    pub fn new(config: &config::Sqs, payload_store: Option<PayloadStore>, sdk_config: &SdkConfig) -> Self {
+        let endpoint_url = Some(config.api_endpoint_url.clone())
+            .filter(|url| !url.is_empty());
+        let mut sqs_config = aws_sdk_sqs::config::Builder::from(sdk_config);
+        sqs_config.set_endpoint_url(endpoint_url);
         Queue {
-            queue_url,
-            visibility_timeout,
-            client: Client::new(sdk_config),
+            queue_url: config.queue_url.clone(),
+            visibility_timeout: config.visibility_timeout,
+            client: Client::from_conf(sqs_config.build()),
+            cloudwatch_client: aws_sdk_cloudwatch::Client::new(sdk_config),
+            payload_store,
         }
     }
 
+    /// Create a client for the configured queue, using the default AWS credentials and region.
+    pub async fn from_config(config: &config::Queue) -> Self {
+        let aws_config = aws_config::defaults(BehaviorVersion::v2024_03_28()).load().await;
+        let payload_store = config.s3.as_ref()
+            .map(|s3_config| PayloadStore::new(s3_config, &aws_config));
+        Queue::new(&config.sqs, payload_store, &aws_config)
+    }
+
+    /// Check that the queue exists, and that we have permission to access it.
+    pub async fn check_access(&self) -> Result<()> {
+        self.client.get_queue_attributes()
+            .queue_url(&self.queue_url)
+            .attribute_names(QueueAttributeName::QueueArn)
+            .send().await?;
+        Ok(())
+    }
+
+    /// Retrieve the approximate number of messages in the queue.
+    pub async fn stats(&self) -> Result<QueueStats> {
+        let output = self.client.get_queue_attributes()
+            .queue_url(&self.queue_url)
+            .attribute_names(QueueAttributeName::ApproximateNumberOfMessages)
+            .attribute_names(QueueAttributeName::ApproximateNumberOfMessagesNotVisible)
+            .attribute_names(QueueAttributeName::ApproximateNumberOfMessagesDelayed)
+            .send().await?;
+        let attributes = output.attributes.unwrap_or_default();
+        let count = |name: QueueAttributeName| attributes.get(&name)
+            .and_then(|value| value.parse().ok())
+            .unwrap_or_default();
+        Ok(QueueStats {
+            visible: count(QueueAttributeName::ApproximateNumberOfMessages),
+            not_visible: count(QueueAttributeName::ApproximateNumberOfMessagesNotVisible),
+            delayed: count(QueueAttributeName::ApproximateNumberOfMessagesDelayed),
+        })
+    }
+
+    /// Retrieve the age of the oldest message in the queue, from the most recent datapoint of the
+    /// `ApproximateAgeOfOldestMessage` CloudWatch metric. SQS publishes this metric every minute,
+    /// so it lags behind the queue's actual state. Returns `None` if there's no recent datapoint.
+    pub async fn oldest_message_age(&self) -> Result<Option<Duration>> {
+        let now = SystemTime::now();
+        let output = self.cloudwatch_client.get_metric_statistics()
+            .namespace("AWS/SQS")
+            .metric_name("ApproximateAgeOfOldestMessage")
+            .dimensions(Dimension::builder().name("QueueName").value(self.name()).build())
+            .start_time(DateTime::from(now - METRIC_LOOKBACK))
+            .end_time(DateTime::from(now))
+            .period(60)
+            .statistics(Statistic::Maximum)
+            .send().await?;
+        let age = output.datapoints.unwrap_or_default()
+            .into_iter()
+            .filter(|datapoint| datapoint.timestamp.is_some())
+            .max_by_key(|datapoint| datapoint.timestamp)
+            .and_then(|datapoint| datapoint.maximum)
+            .map(|seconds| Duration::from_secs_f64(seconds.max(0.0)));
+        Ok(age)
+    }
+
+    /// The name of the queue, as determined by the last path segment of its URL.
+    pub fn name(&self) -> &str {
+        name_from_url(&self.queue_url)
+    }
+
+    /// Add a message to the queue, returning its ID. The group ID and deduplication ID are only
+    /// applicable to FIFO queues.
+    pub async fn send(
+        &self,
+        body: String,
+        attributes: HashMap<String, String>,
+        group_id: Option<String>,
+        deduplication_id: Option<String>,
+        delay_seconds: Option<i32>,
+    ) -> Result<String> {
+        let mut request = self.client.send_message()
+            .queue_url(&self.queue_url)
+            .message_body(body)
+            .set_message_group_id(group_id)
+            .set_message_deduplication_id(deduplication_id)
+            .set_delay_seconds(delay_seconds);
+        for (name, value) in attributes {
+            let value = MessageAttributeValue::builder()
+                .data_type("String")
+                .string_value(value)
+                .build()?;
+            request = request.message_attributes(name, value);
+        }
+        let output = request.send().await?;
+        output.message_id.ok_or(Error::MissingMessageId)
+    }
+
+    /// Add a copy of an item received from another queue (e.g. a dead-letter queue) to this queue,
+    /// preserving its body and message attributes, and returning the new message ID.
+    pub async fn send_item(&self, item: &Item) -> Result<String> {
+        //FIFO queues need a deduplication ID. Don't reuse the original one, since the copy would be
+        //discarded as a duplicate if the original was sent within the deduplication interval.
+        let group_id = item.metadata.get("MessageGroupId").cloned();
+        let deduplication_id = group_id.as_ref()
+            .map(|_| format!("{}-{}", item.id, Utc::now().timestamp_millis()));
+        let message_attributes = Some(item.message_attributes.clone())
+            .filter(|attributes| !attributes.is_empty());
+
+        let output = self.client.send_message()
+            .queue_url(&self.queue_url)
+            .message_body(String::from_utf8_lossy(&item.data))
+            .set_message_attributes(message_attributes)
+            .set_message_group_id(group_id)
+            .set_message_deduplication_id(deduplication_id)
+            .send().await?;
+        output.message_id.ok_or(Error::MissingMessageId)
+    }
+
     /// Retrieve the next item from the queue. If no items are available, wait up to
     /// `wait_duration` for an item to arrive. If there are still no items, return `None`.
     pub async fn receive(&self, wait_duration: Duration) -> Result<Option<Item>> {
@@ -43,7 +185,8 @@ impl Queue {
             return Ok(None);
         }
 
-        let item: Item = messages.remove(0).try_into()?;
+        let mut item: Item = messages.remove(0).try_into()?;
+        item.queue = self.name().to_string();
         Ok(Some(item))
     }
 
@@ -58,39 +201,79 @@ impl Queue {
             .receipt_handle(receipt_handle)
             .send().await?;
 
+        if let Some(payload_store) = &self.payload_store {
+            if let Err(e) = payload_store.delete(item).await {
+                log::warn!(task_id = item.id; "failed to delete offloaded payload: {:#}", anyhow::anyhow!(e));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Make an item which is being processed visible to consumers of the queue again immediately,
+    /// so it can be re-attempted without waiting for its visibility timeout to expire.
+    pub async fn release(&self, receipt_handle: &str) -> Result<()> {
+        self.defer(receipt_handle, 0).await
+    }
+
+    /// Make an item which is being processed visible to consumers of the queue again after the
+    /// given number of seconds, instead of when its visibility timeout expires.
+    pub async fn defer(&self, receipt_handle: &str, seconds: i32) -> Result<()> {
+        self.client.change_message_visibility()
+            .queue_url(&self.queue_url)
+            .receipt_handle(receipt_handle)
+            .visibility_timeout(seconds)
+            .send().await?;
+        Ok(())
+    }
+
+    /// If the item's body points to a payload which was offloaded to S3, retrieve the payload.
+    pub async fn fetch_payload(&self, item: &mut Item) -> Result<()> {
+        if let Some(payload_store) = &self.payload_store {
+            payload_store.fetch(item).await?;
+        }
         Ok(())
     }
 }
 
+/// Determine the name of a queue from the last path segment of its URL.
+pub fn name_from_url(queue_url: &str) -> &str {
+    queue_url.trim_end_matches('/').rsplit('/').next().unwrap_or_default()
+}
+
 impl TryFrom<Message> for Item {
     type Error = Error;
 
     fn try_from(value: Message) -> result::Result<Self, Self::Error> {
         let mut item = Item {
             id: value.message_id.ok_or(Error::MissingMessageId)?,
+            queue: String::new(),
             data: Vec::new(),
             metadata: HashMap::new(),
+            message_attributes: HashMap::new(),
+            previous_response_headers: HashMap::new(),
         };
 
         let receipt_handle = value.receipt_handle.ok_or(Error::MissingReceiptHandle)?;
         item.metadata.insert("receipt_handle".to_string(), receipt_handle);
 
-        if value.body.is_some() {
-            item.data = value.body.unwrap().into_bytes();
+        if let Some(body) = value.body {
+            item.data = body.into_bytes();
         }
 
-        if value.message_attributes.is_some() {
-            for (key, val) in value.message_attributes.unwrap().iter() {
+        if let Some(message_attributes) = value.message_attributes {
+            for (key, val) in message_attributes.iter() {
                 if let Some(val) = &val.string_value {
-                    log::debug!("[task {}] MessageAttribute[{}] = {}", &item.id, key, val);
+                    log::debug!(task_id = item.id; "MessageAttribute[{}] = {}", key, val);
                     item.metadata.insert(key.clone(), val.clone());
                 }
             }
+            item.message_attributes = message_attributes;
         }
 
         if let Some(system_attributes) = value.attributes {
             for (key, val) in system_attributes.iter() {
-                log::debug!("[task {}] MessageSystemAttribute[{}] = {}", &item.id, key, val);
+                log::debug!(task_id = item.id; "MessageSystemAttribute[{}] = {}", key, val);
                 item.metadata.insert(key.to_string(), val.clone());
             }
         }
@@ -105,15 +288,46 @@ impl TryFrom<Message> for Item {
 //
 
 #[derive(Debug, Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     #[error("SQS ReceiveMessage API call failed")]
     SqsReceiveMessageError(#[from] ReceiveMessageError),
     #[error("SQS DeleteMessage API call failed")]
     SqsDeleteMessageError(#[from] DeleteMessageError),
+    #[error("SQS SendMessage API call failed")]
+    SqsSendMessageError(#[from] SendMessageError),
+    #[error("invalid message attribute")]
+    InvalidMessageAttribute(#[from] BuildError),
+    #[error("SQS GetQueueAttributes API call failed")]
+    SqsGetQueueAttributesError(#[from] GetQueueAttributesError),
+    #[error("SQS ChangeMessageVisibility API call failed")]
+    SqsChangeMessageVisibilityError(#[from] ChangeMessageVisibilityError),
+    #[error("CloudWatch GetMetricStatistics API call failed")]
+    CloudWatchGetMetricStatisticsError(#[from] GetMetricStatisticsError),
     #[error("invalid message model received: missing MessageId")]
     MissingMessageId,
     #[error("invalid message model received: missing ReceiptHandle")]
     MissingReceiptHandle,
+    #[error(transparent)]
+    Offload(#[from] offload::Error),
+}
+
+impl Error {
+    /// The name of the error variant, suitable for use as a metric label.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::SqsReceiveMessageError(_) => "SqsReceiveMessageError",
+            Error::SqsDeleteMessageError(_) => "SqsDeleteMessageError",
+            Error::SqsSendMessageError(_) => "SqsSendMessageError",
+            Error::InvalidMessageAttribute(_) => "InvalidMessageAttribute",
+            Error::SqsGetQueueAttributesError(_) => "SqsGetQueueAttributesError",
+            Error::SqsChangeMessageVisibilityError(_) => "SqsChangeMessageVisibilityError",
+            Error::CloudWatchGetMetricStatisticsError(_) => "CloudWatchGetMetricStatisticsError",
+            Error::MissingMessageId => "MissingMessageId",
+            Error::MissingReceiptHandle => "MissingReceiptHandle",
+            Error::Offload(_) => "Offload",
+        }
+    }
 }
 
 impl From<SdkError<ReceiveMessageError>> for Error {
@@ -128,4 +342,28 @@ impl From<SdkError<DeleteMessageError>> for Error {
     }
 }
 
+impl From<SdkError<SendMessageError>> for Error {
+    fn from(value: SdkError<SendMessageError>) -> Self {
+        Error::SqsSendMessageError(value.into_service_error())
+    }
+}
+
+impl From<SdkError<GetQueueAttributesError>> for Error {
+    fn from(value: SdkError<GetQueueAttributesError>) -> Self {
+        Error::SqsGetQueueAttributesError(value.into_service_error())
+    }
+}
+
+impl From<SdkError<ChangeMessageVisibilityError>> for Error {
+    fn from(value: SdkError<ChangeMessageVisibilityError>) -> Self {
+        Error::SqsChangeMessageVisibilityError(value.into_service_error())
+    }
+}
+
+impl From<SdkError<GetMetricStatisticsError>> for Error {
+    fn from(value: SdkError<GetMetricStatisticsError>) -> Self {
+        Error::CloudWatchGetMetricStatisticsError(value.into_service_error())
+    }
+}
+
 pub type Result<T> = result::Result<T, Error>;
@@ -0,0 +1,295 @@
+use crate::config::{KeyRateLimit, RateLimit};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+/// The maximum number of per-key buckets. Beyond this, buckets which have refilled completely are
+/// discarded (a full bucket behaves the same as a new one), or failing that, the least recently
+/// used bucket.
+const MAX_KEY_BUCKETS: usize = 10_000;
+
+//
+// Data structures
+//
+
+/// Limits the rate at which tasks are dispatched, overall and for tasks with the same key, using
+/// token buckets.
+pub struct RateLimiter {
+    state: Mutex<State>,
+}
+
+struct State {
+    config: Option<RateLimit>,
+    overall: Option<TokenBucket>,
+    keys: HashMap<String, TokenBucket>,
+}
+
+/// A bucket which fills with tokens at a steady rate, up to its capacity. Each task dispatched
+/// takes a token.
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+
+//
+// Functions
+//
+
+impl RateLimiter {
+    pub fn new(config: Option<RateLimit>) -> Self {
+        RateLimiter { state: Mutex::new(State::new(config)) }
+    }
+
+    /// Apply a new configuration. If it has changed, all buckets start again full.
+    pub fn reconfigure(&self, config: Option<RateLimit>) {
+        let mut state = self.state.lock().unwrap();
+        if state.config != config {
+            *state = State::new(config);
+        }
+    }
+
+    /// Wait until the overall rate allows another task, and take a token for it.
+    pub async fn acquire(&self) {
+        loop {
+            let wait = match &mut self.state.lock().unwrap().overall {
+                Some(bucket) => match bucket.take() {
+                    Ok(()) => return,
+                    Err(wait) => wait,
+                },
+                None => return,
+            };
+            sleep(wait).await;
+        }
+    }
+
+    /// Return a token taken by `acquire` which wasn't used to dispatch a task.
+    pub fn refund(&self) {
+        if let Some(bucket) = &mut self.state.lock().unwrap().overall {
+            bucket.refund();
+        }
+    }
+
+    /// Take a token for a key. If the key's rate doesn't allow another task yet, return how long
+    /// until it will.
+    pub fn take_for_key(&self, key: &str) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        let State { config, keys, .. } = &mut *state;
+        let Some(key_config) = config.as_ref().and_then(|config| config.key.as_ref()) else {
+            return Ok(());
+        };
+
+        if !keys.contains_key(key) {
+            let Some((rate, burst)) = rate_for_key(key_config, key) else {
+                return Ok(());
+            };
+            if keys.len() >= MAX_KEY_BUCKETS {
+                keys.retain(|_, bucket| !bucket.is_full());
+            }
+            if keys.len() >= MAX_KEY_BUCKETS {
+                let least_recently_used = keys.iter()
+                    .min_by_key(|(_, bucket)| bucket.updated)
+                    .map(|(key, _)| key.clone());
+                if let Some(least_recently_used) = least_recently_used {
+                    keys.remove(&least_recently_used);
+                }
+            }
+            keys.insert(key.to_string(), TokenBucket::new(rate, burst));
+        }
+        keys.get_mut(key).map_or(Ok(()), TokenBucket::take)
+    }
+
+    /// Return a token taken by `take_for_key` which wasn't used to dispatch a task.
+    pub fn refund_key(&self, key: &str) {
+        if let Some(bucket) = self.state.lock().unwrap().keys.get_mut(key) {
+            bucket.refund();
+        }
+    }
+}
+
+impl State {
+    fn new(config: Option<RateLimit>) -> Self {
+        let overall = config.as_ref()
+            .and_then(|config| Some(TokenBucket::new(config.rate?, config.burst)));
+        State { config, overall, keys: HashMap::new() }
+    }
+}
+
+/// The rate and burst size for a key: its own, if it's listed, otherwise the default for all keys.
+fn rate_for_key(config: &KeyRateLimit, key: &str) -> Option<(f64, Option<u32>)> {
+    match config.rates.get(key) {
+        Some(rate) => Some((rate.rate, rate.burst)),
+        None => Some((config.rate?, config.burst)),
+    }
+}
+
+impl TokenBucket {
+    /// Create a full bucket. The capacity defaults to the rate, rounded up.
+    fn new(rate: f64, burst: Option<u32>) -> Self {
+        let capacity = burst.map(f64::from).unwrap_or(rate.ceil()).max(1.0);
+        TokenBucket { rate, capacity, tokens: capacity, updated: Instant::now() }
+    }
+
+    /// Take a token, or return how long until one will be available.
+    fn take(&mut self) -> Result<(), Duration> {
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+
+    fn refund(&mut self) {
+        self.tokens = (self.tokens + 1.0).min(self.capacity);
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens + self.updated.elapsed().as_secs_f64() * self.rate >= self.capacity
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.updated).as_secs_f64() * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{FieldSource, Rate};
+
+    fn limiter(key_rate: f64, key_burst: Option<u32>) -> RateLimiter {
+        RateLimiter::new(Some(RateLimit {
+            rate: Some(1.0),
+            burst: Some(2),
+            key: Some(KeyRateLimit {
+                source: FieldSource::Metadata,
+                field: "route".to_string(),
+                rate: Some(key_rate),
+                burst: key_burst,
+                rates: HashMap::from([("slow".to_string(), Rate { rate: 0.5, burst: None })]),
+            }),
+        }))
+    }
+
+    /// Make a bucket look as if it was last updated `seconds` ago.
+    fn age(bucket: &mut TokenBucket, seconds: f64) {
+        bucket.updated = Instant::now() - Duration::from_secs_f64(seconds);
+    }
+
+    #[test]
+    fn bucket_starts_full_at_burst_size() {
+        let mut bucket = TokenBucket::new(10.0, Some(3));
+        for _ in 0..3 {
+            assert!(bucket.take().is_ok());
+        }
+        let wait = bucket.take().unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn bucket_capacity_defaults_to_rate_rounded_up() {
+        assert_eq!(TokenBucket::new(2.5, None).capacity, 3.0);
+        assert_eq!(TokenBucket::new(0.1, None).capacity, 1.0);
+    }
+
+    #[test]
+    fn bucket_refills_at_rate() {
+        let mut bucket = TokenBucket::new(2.0, Some(5));
+        bucket.tokens = 0.0;
+        age(&mut bucket, 1.0);
+        assert!(bucket.take().is_ok());
+        assert!(bucket.take().is_ok());
+        assert!(bucket.take().is_err());
+    }
+
+    #[test]
+    fn bucket_refills_up_to_capacity() {
+        let mut bucket = TokenBucket::new(2.0, Some(5));
+        bucket.tokens = 0.0;
+        age(&mut bucket, 100.0);
+        bucket.refill();
+        assert_eq!(bucket.tokens, 5.0);
+        assert!(bucket.is_full());
+    }
+
+    #[test]
+    fn refund_never_exceeds_capacity() {
+        let mut bucket = TokenBucket::new(1.0, Some(2));
+        bucket.refund();
+        assert_eq!(bucket.tokens, 2.0);
+        bucket.take().unwrap();
+        bucket.refund();
+        bucket.refund();
+        assert_eq!(bucket.tokens, 2.0);
+    }
+
+    #[test]
+    fn refund_returns_overall_and_key_tokens() {
+        let limiter = limiter(1.0, Some(1));
+        assert!(limiter.take_for_key("a").is_ok());
+        assert!(limiter.take_for_key("a").is_err());
+        limiter.refund_key("a");
+        assert!(limiter.take_for_key("a").is_ok());
+
+        let mut state = limiter.state.lock().unwrap();
+        let overall = state.overall.as_mut().unwrap();
+        overall.take().unwrap();
+        overall.take().unwrap();
+        drop(state);
+        limiter.refund();
+        limiter.refund();
+        limiter.refund();
+        assert_eq!(limiter.state.lock().unwrap().overall.as_ref().unwrap().tokens, 2.0);
+    }
+
+    #[test]
+    fn listed_keys_use_their_own_rate() {
+        let limiter = limiter(10.0, None);
+        assert!(limiter.take_for_key("slow").is_ok());
+        assert!(limiter.take_for_key("slow").is_err());
+        for _ in 0..10 {
+            assert!(limiter.take_for_key("fast").is_ok());
+        }
+    }
+
+    #[test]
+    fn full_buckets_are_evicted_first() {
+        let limiter = limiter(0.001, Some(1));
+        for i in 0..MAX_KEY_BUCKETS {
+            limiter.take_for_key(&format!("key-{}", i)).unwrap();
+        }
+        limiter.state.lock().unwrap().keys.get_mut("key-5").unwrap().tokens = 1.0;
+
+        limiter.take_for_key("new").unwrap();
+        let state = limiter.state.lock().unwrap();
+        assert_eq!(state.keys.len(), MAX_KEY_BUCKETS);
+        assert!(!state.keys.contains_key("key-5"));
+        assert!(state.keys.contains_key("new"));
+    }
+
+    #[test]
+    fn least_recently_used_bucket_is_evicted() {
+        let limiter = limiter(0.001, Some(1));
+        for i in 0..MAX_KEY_BUCKETS {
+            limiter.take_for_key(&format!("key-{}", i)).unwrap();
+        }
+        age(limiter.state.lock().unwrap().keys.get_mut("key-7").unwrap(), 1.0);
+
+        limiter.take_for_key("new").unwrap();
+        let state = limiter.state.lock().unwrap();
+        assert_eq!(state.keys.len(), MAX_KEY_BUCKETS);
+        assert!(!state.keys.contains_key("key-7"));
+        assert!(state.keys.contains_key("new"));
+        //An evicted key which comes back starts again with a full bucket
+        drop(state);
+        assert!(limiter.take_for_key("key-7").is_ok());
+    }
+}
@@ -0,0 +1,182 @@
+use crate::cli::RedriveArgs;
+use crate::config::Config;
+use crate::decoding;
+use crate::item::Item;
+use crate::mapping::{self, Mappings};
+use crate::pool::{HttpResponse, Pool};
+use crate::queue::Queue;
+use anyhow::{anyhow, bail, Error};
+use std::collections::HashSet;
+use std::time::Duration;
+use tokio::time::{interval, MissedTickBehavior};
+
+/// How long to wait for more messages to arrive on the dead-letter queue before concluding that
+/// it has been emptied.
+const RECEIVE_WAIT: Duration = Duration::from_secs(1);
+
+/// The highest `--rate` accepted, in messages per second.
+const MAX_RATE: f64 = 1e9;
+
+//
+// Data structures
+//
+
+/// Criteria which a message must meet to be redriven.
+struct Filter<'a> {
+    attributes: &'a [(String, String)],
+    body_fields: &'a [(String, String)],
+}
+
+#[derive(Default)]
+struct Summary {
+    matched: usize,
+    redriven: usize,
+    failed: usize,
+    skipped: usize,
+}
+
+
+//
+// Functions
+//
+
+/// Move messages from a dead-letter queue back to the configured queue (or, in direct mode,
+/// dispatch them straight to the FastCGI server). Messages are held invisible while the
+/// dead-letter queue is scanned, and any which weren't redriven are released at the end.
+pub async fn run(config: Config, args: RedriveArgs) -> Result<(), Error> {
+    if args.rate.is_some_and(|rate| !(rate.is_finite() && rate > 0.0 && rate <= MAX_RATE)) {
+        bail!("--rate must be greater than 0, and at most {}", MAX_RATE);
+    }
+    let filter = Filter { attributes: &args.attributes, body_fields: &args.body_fields };
+
+    //When moving a message, its offloaded payload must be left in place for the copy to refer to
+    let mut dlq_config = config.queue.clone();
+    dlq_config.sqs.queue_url = args.from.clone();
+    if let Some(s3_config) = dlq_config.s3.as_mut().filter(|_| !args.direct) {
+        s3_config.delete_after_acknowledge = false;
+    }
+    let dlq = Queue::from_config(&dlq_config).await;
+    let queue = Queue::from_config(&config.queue).await;
+    let pool = Pool::new(
+        config.fastcgi.address.clone(),
+        config.fastcgi.port,
+        config.fastcgi.script_path.clone(),
+    );
+    let mappings = mapping::compile(&config.field_mappings)?;
+    let mut rate_limit = args.rate.map(|rate| {
+        let mut rate_limit = interval(Duration::from_secs_f64(1.0 / rate));
+        rate_limit.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        rate_limit
+    });
+
+    let mut summary = Summary::default();
+    let mut seen = HashSet::new();
+    let mut held = Vec::new();
+    while args.limit.is_none_or(|limit| summary.matched < limit) {
+        let Some(mut item) = dlq.receive(RECEIVE_WAIT).await? else {
+            break;
+        };
+        //If a message comes round again, its visibility timeout has expired, so we've seen
+        //everything there is to see
+        if !seen.insert(item.id.clone()) {
+            held.push(item);
+            break;
+        }
+        let matches = filter.matches(&item, &dlq, &config).await.unwrap_or_else(|e| {
+            eprintln!("unable to decode {}, so it doesn't match the filters: {:#}", item.id, e);
+            false
+        });
+        if !matches {
+            summary.skipped += 1;
+            held.push(item);
+            continue;
+        }
+        summary.matched += 1;
+        if args.dry_run {
+            println!("would redrive {}", item.id);
+            held.push(item);
+            continue;
+        }
+
+        if let Some(rate_limit) = &mut rate_limit {
+            rate_limit.tick().await;
+        }
+        let result = if args.direct {
+            dispatch(&mut item, &dlq, &pool, &config, &mappings).await
+        } else {
+            queue.send_item(&item).await.map(|_| ()).map_err(Error::from)
+        };
+        match result {
+            Ok(()) => {
+                //The message has been moved or dispatched, so it mustn't be released, even if it
+                //can't be removed from the dead-letter queue
+                if let Err(e) = dlq.acknowledge(&item).await {
+                    summary.failed += 1;
+                    eprintln!(
+                        "{} {}, but failed to remove it from the dead-letter queue: {:#}",
+                        if args.direct { "dispatched" } else { "moved" }, item.id, anyhow!(e)
+                    );
+                    continue;
+                }
+                summary.redriven += 1;
+                println!("{} {}", if args.direct { "dispatched" } else { "moved" }, item.id);
+            },
+            Err(e) => {
+                summary.failed += 1;
+                eprintln!("failed {}: {:#}", item.id, e);
+                held.push(item);
+            },
+        }
+    }
+
+    //Make the messages we didn't redrive visible again
+    for item in held {
+        if let Some(receipt_handle) = item.metadata.get("receipt_handle") {
+            if let Err(e) = dlq.release(receipt_handle).await {
+                eprintln!("failed to release {}: {:#}", item.id, anyhow!(e));
+            }
+        }
+    }
+
+    eprintln!(
+        "{} messages matched, {} redriven, {} failed, {} didn't match the filters",
+        summary.matched, summary.redriven, summary.failed, summary.skipped
+    );
+    if summary.failed > 0 {
+        bail!("{} messages could not be redriven", summary.failed);
+    }
+    Ok(())
+}
+
+/// Dispatch an item to the FastCGI server, as the runner would, failing if the script doesn't
+/// return a successful status code.
+async fn dispatch(item: &mut Item, dlq: &Queue, pool: &Pool, config: &Config, mappings: &Mappings) -> Result<(), Error> {
+    dlq.fetch_payload(item).await?;
+    decoding::decode(item, &config.body_decoding, config.max_decoded_bytes)?;
+    let env = mapping::map_fields(item, mappings)?;
+    let response: HttpResponse = pool.dispatch(&item.data, &config.fastcgi.cgi_environment, env).await?.try_into()?;
+    if !response.status().is_success() {
+        bail!("script returned status code {}", response.status());
+    }
+    Ok(())
+}
+
+impl Filter<'_> {
+    /// Whether an item meets the criteria. They are checked against a copy of the item, with its
+    /// payload fetched and decoded as the runner would, so that the item itself is redriven as it
+    /// was received.
+    async fn matches(&self, item: &Item, dlq: &Queue, config: &Config) -> Result<bool, Error> {
+        if self.attributes.is_empty() && self.body_fields.is_empty() {
+            return Ok(true);
+        }
+        let mut decoded = item.clone();
+        dlq.fetch_payload(&mut decoded).await?;
+        decoding::decode(&mut decoded, &config.body_decoding, config.max_decoded_bytes)?;
+        Ok(
+            self.attributes.iter()
+                .all(|(name, value)| decoded.metadata.get(name) == Some(value))
+            && self.body_fields.iter()
+                .all(|(name, value)| decoded.get_string_from_data_json_object(name).as_ref() == Some(value))
+        )
+    }
+}
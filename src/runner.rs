@@ -1,13 +1,27 @@
-use crate::config::{FieldMappings, FieldSource};
+use crate::audit::{self, AuditLog};
+use crate::breaker::CircuitBreaker;
+use crate::config::{ConcurrencyKey, Config, DecodingStep, RateLimit, MAX_VISIBILITY_TIMEOUT};
+use crate::control::{Control, Settings};
+use crate::decoding;
+use crate::health::Health;
 use crate::item::Item;
-use crate::pool::{HttpResponse, Pool};
-use crate::queue::Queue;
-use anyhow::{anyhow, Context};
-use std::collections::HashMap;
-use std::sync::Arc;
-use std::time::Duration;
-use tokio::task::{JoinHandle, JoinSet};
-use tokio::time::sleep;
+use crate::mapping::{self, Mappings};
+use crate::metrics;
+use crate::pool::{self, HttpResponse, Pool};
+use crate::queue::{self, Queue};
+use crate::ratelimit::RateLimiter;
+use crate::telemetry;
+use anyhow::{anyhow, Chain};
+use chrono::Utc;
+use http::StatusCode;
+use opentelemetry::KeyValue;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::task::{spawn_blocking, JoinHandle, JoinSet};
+use tokio::time::{sleep, timeout};
+use tokio::sync::watch;
 use tokio::{select, spawn};
 use tokio_util::sync::CancellationToken;
 
@@ -18,10 +32,21 @@ pub struct Runner {
 }
 
 impl Runner {
-    pub fn start(max_tasks: usize, pool: Arc<Pool>, queue: Arc<Queue>, mapping_config: FieldMappings) -> Self {
+    pub fn start(
+        pool: Arc<Pool>,
+        queue: Arc<Queue>,
+        task_config: TaskConfig,
+        breaker: CircuitBreaker,
+        health: Arc<Health>,
+        audit: Option<AuditLog>,
+        control: Arc<Control>,
+    ) -> Self {
         let inner = Arc::new(_Runner {
-            max_tasks, pool, queue,
-            mapping_config: Arc::new(mapping_config),
+            pool, queue, breaker, health, audit, control,
+            rate_limiter: RateLimiter::new(task_config.rate_limit.clone()),
+            task_config: RwLock::new(Arc::new(task_config)),
+            history: ResponseHistory::default(),
+            key_slots: KeySlots::default(),
             cancellation: CancellationToken::new(),
         });
 
@@ -31,58 +56,167 @@ impl Runner {
         }
     }
 
-    pub async fn stop(self) {
+    /// Replace the configuration used to prepare items for dispatch. Tasks already in flight are
+    /// unaffected.
+    pub fn reconfigure(&self, task_config: TaskConfig) {
+        self.inner.rate_limiter.reconfigure(task_config.rate_limit.clone());
+        *self.inner.task_config.write().unwrap() = Arc::new(task_config);
+    }
+
+    /// Stop polling for new tasks, and wait for the tasks in flight to finish. Any which are still
+    /// running after `drain_timeout` are abandoned, and their items are released back to the queue.
+    pub async fn stop(mut self, drain_timeout: Duration) {
         self.inner.cancellation.cancel();
+        if timeout(drain_timeout, &mut self.join_handle).await.is_ok() {
+            return;
+        }
+        log::warn!("Tasks did not finish within {}s; abandoning them", drain_timeout.as_secs());
+        self.join_handle.abort();
         _ = self.join_handle.await;
+        self.inner.release_abandoned().await;
     }
 }
 
 struct _Runner {
-    max_tasks: usize,
     pool: Arc<Pool>,
     queue: Arc<Queue>,
-    mapping_config: Arc<FieldMappings>,
+    task_config: RwLock<Arc<TaskConfig>>,
+    history: ResponseHistory,
+    key_slots: KeySlots,
+    rate_limiter: RateLimiter,
+    breaker: CircuitBreaker,
+    health: Arc<Health>,
+    audit: Option<AuditLog>,
+    control: Arc<Control>,
     cancellation: CancellationToken,
 }
 
+/// The configuration used to prepare and dispatch items, which can be replaced while running.
+pub struct TaskConfig {
+    pub cgi_environment: HashMap<String, String>,
+    pub decoding_config: Vec<DecodingStep>,
+    pub max_decoded_bytes: u64,
+    pub mappings: Mappings,
+    pub concurrency_key: Option<ConcurrencyKey>,
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// Remembers the response headers from failed attempts at processing items, so they can be made
+/// available to the next attempt.
+#[derive(Default)]
+struct ResponseHistory {
+    entries: Mutex<ResponseHistoryEntries>,
+}
+
+#[derive(Default)]
+struct ResponseHistoryEntries {
+    headers: HashMap<String, HashMap<String, String>>,
+    order: VecDeque<String>,
+}
+
+/// Counts the tasks in flight for each concurrency key.
+#[derive(Default)]
+struct KeySlots {
+    busy: Mutex<HashMap<String, usize>>,
+}
+
+/// Details of the script's execution which are recorded in the audit log.
+#[derive(Default)]
+struct TaskOutput {
+    env: BTreeMap<String, String>,
+    status: Option<u16>,
+    stdout: String,
+    stderr: String,
+}
+
+const MAX_RESPONSE_HISTORY: usize = 10_000;
+
+/// How often to report that the runner is alive while it waits for a worker to become available.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
 impl _Runner {
-    async fn run(&self) {
+    async fn run(self: &Arc<Self>) {
         let mut tasks = JoinSet::new();
+        let mut settings = self.control.subscribe();
         loop {
-            log::debug!("{} of {} workers are busy; polling for new tasks", tasks.len(), self.max_tasks);
-
-            //Poll for items on the queue. Block until one of these events:
-            // 1. An item becomes available, or 20 seconds have elapsed and still no items are available
-            // 2. The runner receives a stop request
-            select! {
-                poll_result = self.queue.receive(Duration::from_secs(20)) => {
-                    match poll_result {
-                        Ok(item) => {
-                            if let Some(item) = item {
-                                //Spawn a task to handle this item
-                                log::debug!("dispatching task {}", &item.id);
-                                tasks.spawn(
-                                    consume_item(item, Arc::clone(&self.pool), Arc::clone(&self.queue), Arc::clone(&self.mapping_config))
-                                );
+            self.health.heartbeat();
+            let Settings { paused, max_tasks } = *settings.borrow_and_update();
+            metrics::MAX_SLOTS.set(max_tasks as i64);
+            metrics::BUSY_SLOTS.set(tasks.len() as i64);
+
+            if paused {
+                //Polling is paused. Block until the settings change, or the runner receives a stop request.
+                log::debug!("polling is paused; {} tasks are still running", tasks.len());
+                select! {
+                    _ = settings.changed() => {}
+                    _ = tasks.join_next(), if !tasks.is_empty() => {}
+                    _ = sleep(HEARTBEAT_INTERVAL) => {}
+                    _ = self.cancellation.cancelled() => {}
+                }
+            } else if !self.breaker.is_closed() {
+                //FastCGI isn't accepting connections, so don't receive any items until it is.
+                //Block until it is, or the runner receives a stop request, or polling is paused.
+                log::debug!("circuit breaker is open; not polling for new tasks");
+                select! {
+                    _ = self.breaker.wait_until_closed(&self.pool) => {}
+                    _ = self.cancellation.cancelled() => {}
+                    _ = async { _ = settings.wait_for(|settings| settings.paused).await; } => {}
+                    _ = sleep(HEARTBEAT_INTERVAL) => {}
+                }
+            } else if !self.wait_for_rate_limit(&mut settings).await {
+                //The rate limit didn't allow another task before the runner was stopped or paused,
+                //or it's time for a heartbeat
+            } else {
+                log::debug!("{} of {} workers are busy; polling for new tasks", tasks.len(), max_tasks);
+
+                //Poll for items on the queue. Block until one of these events:
+                // 1. An item becomes available, or 20 seconds have elapsed and still no items are available
+                // 2. The runner receives a stop request
+                // 3. Polling is paused
+                select! {
+                    poll_result = self.queue.receive(Duration::from_secs(20)) => {
+                        match poll_result {
+                            Ok(item) => {
+                                self.health.record_receive(None);
+                                if let Some(mut item) = item {
+                                    //Spawn a task to handle this item
+                                    log::debug!(task_id = item.id; "dispatching task");
+                                    item.previous_response_headers = self.history.get(&item.id);
+                                    tasks.spawn(Arc::clone(self).consume_item(item));
+                                    metrics::BUSY_SLOTS.set(tasks.len() as i64);
+                                } else {
+                                    self.rate_limiter.refund();
+                                }
+                            }
+                            Err(error) => {
+                                self.rate_limiter.refund();
+                                metrics::SQS_RECEIVE_ERRORS.with_label_values(&[error.kind()]).inc();
+                                let message = format!("{:#}", anyhow!(error));
+                                log::error!("An error occurred fetching from the queue (will retry in 5s): {}", message);
+                                self.health.record_receive(Some(message));
+                                sleep(Duration::from_secs(5)).await;
                             }
-                        }
-                        Err(error) => {
-                            log::error!("An error occurred fetching from the queue (will retry in 5s): {:#}", anyhow!(error));
-                            sleep(Duration::from_secs(5)).await;
                         }
                     }
+                    _ = self.cancellation.cancelled() => self.rate_limiter.refund(),
+                    _ = async { _ = settings.wait_for(|settings| settings.paused).await; } => self.rate_limiter.refund(),
                 }
-                _ = self.cancellation.cancelled() => {}
             }
 
-            //If all our workers are now busy, block until a task finishes
-            while tasks.len() >= self.max_tasks {
+            //If all our workers are now busy, block until a task finishes or the limit is raised
+            while tasks.len() >= settings.borrow_and_update().max_tasks && !self.cancellation.is_cancelled() {
                 log::debug!("all workers are busy, not polling for new tasks");
-                tasks.join_next().await;
+                select! {
+                    _ = tasks.join_next() => {}
+                    _ = settings.changed() => {}
+                    _ = sleep(HEARTBEAT_INTERVAL) => self.health.heartbeat(),
+                    _ = self.cancellation.cancelled() => {}
+                }
             }
 
             //Clear any finished tasks out of the JoinSet
-            while let Some(_) = tasks.try_join_next() {}
+            while tasks.try_join_next().is_some() {}
+            metrics::BUSY_SLOTS.set(tasks.len() as i64);
 
             //See if we have received a stop request
             if self.cancellation.is_cancelled() {
@@ -95,66 +229,402 @@ impl _Runner {
             }
         }
     }
+
+    /// Wait until the overall rate limit allows another task, so that items are only received when
+    /// they can be dispatched straight away. Returns false, without waiting for the rate limit, if
+    /// the runner is stopped or paused, or a heartbeat is due.
+    async fn wait_for_rate_limit(&self, settings: &mut watch::Receiver<Settings>) -> bool {
+        select! {
+            _ = self.rate_limiter.acquire() => true,
+            _ = self.cancellation.cancelled() => false,
+            _ = async { _ = settings.wait_for(|settings| settings.paused).await; } => false,
+            _ = sleep(HEARTBEAT_INTERVAL) => false,
+        }
+    }
+
+    /// Take a token from the item's per-key rate limit, and a slot for its concurrency key, if it
+    /// has them, returning the reason the task must be deferred if either is at its limit. Only the
+    /// keys whose source does (or doesn't) read the item's body are checked.
+    fn check_key_limits(
+        &self,
+        item: &Item,
+        task_config: &TaskConfig,
+        reads_body: bool,
+        rate_key: &mut Option<String>,
+        slot_key: &mut Option<String>,
+    ) -> Option<TaskError> {
+        let key_rate_limit = task_config.rate_limit.as_ref().and_then(|rate_limit| rate_limit.key.as_ref());
+        if let Some(key_rate_limit) = key_rate_limit.filter(|config| config.source.reads_body() == reads_body) {
+            if let Some(key) = mapping::resolve(item, &key_rate_limit.source, &key_rate_limit.field) {
+                if let Err(wait) = self.rate_limiter.take_for_key(&key) {
+                    let retry_delay = wait.as_secs_f64().ceil().clamp(1.0, MAX_VISIBILITY_TIMEOUT as f64) as i32;
+                    return Some(TaskError::RateLimit { key, retry_delay });
+                }
+                *rate_key = Some(key);
+            }
+        }
+        let concurrency_key = task_config.concurrency_key.as_ref();
+        if let Some(concurrency_key) = concurrency_key.filter(|config| config.source.reads_body() == reads_body) {
+            if let Some(key) = mapping::resolve(item, &concurrency_key.source, &concurrency_key.field) {
+                if !self.key_slots.acquire(&key, concurrency_key.max_parallel_requests as usize) {
+                    return Some(TaskError::ConcurrencyLimit { key, retry_delay: concurrency_key.retry_delay });
+                }
+                *slot_key = Some(key);
+            }
+        }
+        None
+    }
+
+    /// Release the items of tasks which were abandoned before they finished, so they can be
+    /// re-attempted straight away.
+    async fn release_abandoned(&self) {
+        for (item_id, receipt_handle) in self.control.take_in_flight() {
+            let Some(receipt_handle) = receipt_handle else {
+                continue;
+            };
+            match self.queue.release(&receipt_handle).await {
+                Ok(()) => log::warn!(task_id = item_id; "task abandoned; released it back to the queue"),
+                Err(e) => log::error!(task_id = item_id; "{:#}", anyhow!(e).context("failed to release abandoned task")),
+            }
+        }
+    }
+
+    async fn consume_item(self: Arc<Self>, item: Item) {
+        let started = Instant::now();
+        let received_at = Utc::now();
+        let item_id = item.id.clone();
+        let queue_name = item.queue.clone();
+        let script_path = self.pool.script_path().to_string();
+        let attempt: u64 = item.metadata.get("ApproximateReceiveCount")
+            .and_then(|count| count.parse().ok())
+            .unwrap_or_default();
+        let receipt_handle = item.metadata.get("receipt_handle").cloned();
+        let task_config = Arc::clone(&self.task_config.read().unwrap());
+        let mut output = TaskOutput::default();
+        let mut slot_key = None;
+        let mut rate_key = None;
+        metrics::TASKS_RECEIVED.inc();
+        self.control.start_task(&item);
+
+        let task_cx = telemetry::start_span("consume_item", &telemetry::extract_context(&item.metadata), vec![
+            KeyValue::new("messaging.message.id", item.id.clone()),
+            KeyValue::new("messaging.destination.name", item.queue.clone()),
+        ]);
+
+        //Dispatch the task to the FastCGI pool
+        let result: Result<Item, TaskError> = async {
+            //Only proceed if the item's keys (if it has any) are within their limits. Keys which
+            //don't depend on the body are checked first, to avoid fetching and decoding the
+            //payload of an item which will only be deferred.
+            let mut item = item;
+            if let Some(e) = self.check_key_limits(&item, &task_config, false, &mut rate_key, &mut slot_key) {
+                return Err(e);
+            }
+            self.queue.fetch_payload(&mut item).await?;
+            decoding::decode(&mut item, &task_config.decoding_config, task_config.max_decoded_bytes)?;
+            if let Some(e) = self.check_key_limits(&item, &task_config, true, &mut rate_key, &mut slot_key) {
+                return Err(e);
+            }
+
+            let mapping_cx = telemetry::start_span("field_mapping", &task_cx, vec![]);
+            let env = mapping::map_fields(&item, &task_config.mappings);
+            telemetry::end_span(&mapping_cx, &env);
+            let mut env = env?;
+            if self.audit.is_some() {
+                output.env = env.clone().into_iter().collect();
+            }
+
+            //Don't attempt the task if FastCGI isn't accepting connections
+            if !self.breaker.is_closed() {
+                return Err(TaskError::CircuitOpen);
+            }
+            let dispatch_cx = telemetry::start_span("dispatch", &task_cx, vec![]);
+            if let Some(traceparent) = telemetry::traceparent(&dispatch_cx) {
+                env.insert("HTTP_TRACEPARENT".to_string(), traceparent);
+            }
+            let dispatch_timer = metrics::DISPATCH_DURATION.start_timer();
+            let result = self.pool.dispatch(&item.data, &task_config.cgi_environment, env).await;
+            dispatch_timer.observe_duration();
+            telemetry::end_span(&dispatch_cx, &result);
+            match &result {
+                Err(pool::Error::Io(_)) => self.breaker.record_failure(),
+                _ => self.breaker.record_success(),
+            }
+            let result = result?;
+
+            if let Some(stderr) = result.stderr_string() {
+                if !stderr.is_empty() {
+                    log::warn!(task_id = item.id, stderr = stderr; "script wrote to stderr");
+                }
+                output.stderr = stderr;
+            }
+
+            if let Some(stdout) = result.stdout_string() {
+                log::debug!(task_id = item.id, stdout = stdout; "script wrote to stdout");
+                output.stdout = stdout;
+            }
+
+            let http_response: HttpResponse = result.try_into()?;
+            output.status = Some(http_response.status().as_u16());
+            if !http_response.status().is_success() {
+                self.history.record(&item.id, &http_response);
+                return Err(TaskError::Status(http_response.status()));
+            }
+
+            let status = http_response.status().as_u16();
+            let duration_ms = started.elapsed().as_millis() as u64;
+            if let Ok(body_string) = String::from_utf8(http_response.into_body()) {
+                log::info!(
+                    task_id = item.id, queue = item.queue, script_path, status, duration_ms, attempt;
+                    "task complete: {}", body_string
+                );
+            } else {
+                log::info!(
+                    task_id = item.id, queue = item.queue, script_path, status, duration_ms, attempt;
+                    "task complete"
+                );
+            }
+
+            Ok(item)
+        }.await;
+        if let Some(key) = slot_key {
+            self.key_slots.release(&key);
+        }
+
+        //If the task wasn't attempted, return the item to the queue to be retried later. This
+        //isn't a failure, so it isn't recorded as an outcome.
+        if let Some(retry_delay) = result.as_ref().err().and_then(TaskError::retry_delay) {
+            let e = result.as_ref().err().unwrap();
+            metrics::TASKS_DEFERRED.with_label_values(&[e.reason()]).inc();
+            telemetry::end_span(&task_cx, &result);
+            self.control.defer_task(&item_id);
+            self.rate_limiter.refund();
+            if let Some(key) = &rate_key {
+                self.rate_limiter.refund_key(key);
+            }
+            log::info!(task_id = item_id; "{}; deferring task for {}s", e, retry_delay);
+            if let Some(receipt_handle) = receipt_handle {
+                if let Err(e) = self.queue.defer(&receipt_handle, retry_delay).await {
+                    log::error!(task_id = item_id; "{:#}", anyhow!(e).context("failed to defer task"));
+                }
+            }
+            return;
+        }
+
+        //If the task was successful, remove it from the queue. Otherwise, log the failure.
+        let mut acknowledged = false;
+        match &result {
+            Ok(item) => {
+                metrics::TASKS_SUCCEEDED.inc();
+                self.history.forget(&item.id);
+                let acknowledge_cx = telemetry::start_span("acknowledge", &task_cx, vec![]);
+                let acknowledge_result = self.queue.acknowledge(item).await;
+                telemetry::end_span(&acknowledge_cx, &acknowledge_result);
+                match acknowledge_result {
+                    Ok(()) => acknowledged = true,
+                    Err(e) => {
+                        metrics::SQS_DELETE_ERRORS.with_label_values(&[e.kind()]).inc();
+                        log::error!(task_id = item_id; "{:#}", anyhow!(e).context("failed to remove task from queue"));
+                    },
+                }
+            },
+            Err(e) => {
+                metrics::TASKS_FAILED.with_label_values(&[e.reason(), &e.status()]).inc();
+            }
+        };
+        telemetry::end_span(&task_cx, &result);
+        self.control.finish_task(&item_id, result.is_ok());
+
+        //Record the outcome in the audit log
+        if self.audit.is_some() {
+            let record = audit::Record {
+                message_id: item_id.clone(),
+                queue: queue_name.clone(),
+                received_at,
+                completed_at: Utc::now(),
+                attempt,
+                env: output.env,
+                status: output.status,
+                stdout: output.stdout,
+                stderr: output.stderr,
+                succeeded: result.is_ok(),
+                error: result.as_ref().err().map(|e| Chain::new(e).map(|e| e.to_string()).collect::<Vec<_>>().join(": ")),
+                acknowledged,
+            };
+            let runner = Arc::clone(&self);
+            let write_result = spawn_blocking(move || runner.audit.as_ref().unwrap().write(record)).await
+                .map_err(anyhow::Error::from)
+                .and_then(|result| result.map_err(anyhow::Error::from));
+            if let Err(e) = write_result {
+                log::error!(task_id = item_id; "{:#}", e.context("failed to write audit record"));
+            }
+        }
+
+        //If FastCGI has stopped accepting connections, make the item visible again straight away,
+        //rather than holding it until its visibility timeout expires
+        if let Err(TaskError::Dispatch(pool::Error::Io(_))) = &result {
+            if !self.breaker.is_closed() {
+                if let Some(receipt_handle) = &receipt_handle {
+                    if let Err(e) = self.queue.release(receipt_handle).await {
+                        log::error!(task_id = item_id; "{:#}", anyhow!(e).context("failed to release task"));
+                    }
+                }
+            }
+        }
+
+        if let Err(e) = result {
+            let duration_ms = started.elapsed().as_millis() as u64;
+            if let TaskError::Status(status) = e {
+                let status = status.as_u16();
+                log::error!(
+                    task_id = item_id, queue = queue_name, script_path, status, duration_ms, attempt;
+                    "{:#}", anyhow!(e).context("task failed")
+                );
+            } else {
+                log::error!(
+                    task_id = item_id, queue = queue_name, script_path, duration_ms, attempt;
+                    "{:#}", anyhow!(e).context("task failed")
+                );
+            }
+        }
+    }
+}
+
+impl TaskConfig {
+    pub fn from_config(config: &Config) -> Result<Self, mapping::Error> {
+        Ok(TaskConfig {
+            cgi_environment: config.fastcgi.cgi_environment.clone(),
+            decoding_config: config.body_decoding.clone(),
+            max_decoded_bytes: config.max_decoded_bytes,
+            mappings: mapping::compile(&config.field_mappings)?,
+            concurrency_key: config.concurrency_key.clone(),
+            rate_limit: config.rate_limit.clone(),
+        })
+    }
 }
 
 async fn run(runner: Arc<_Runner>) {
     runner.run().await
 }
 
-async fn consume_item(item: Item, pool: Arc<Pool>, queue: Arc<Queue>, mapping_config: Arc<FieldMappings>) {
-    let item_id = item.id.clone();
+impl KeySlots {
+    /// Take a slot for the given key, unless `limit` tasks with that key are already in flight.
+    fn acquire(&self, key: &str, limit: usize) -> bool {
+        let mut busy = self.busy.lock().unwrap();
+        let count = busy.entry(key.to_string()).or_default();
+        if *count >= limit {
+            return false;
+        }
+        *count += 1;
+        true
+    }
 
-    //Dispatch the task to the FastCGI pool
-    let result = async move {
-        let mut env = HashMap::new();
-        for (key, field_mapping) in mapping_config.iter() {
-            let val = match field_mapping.source {
-                FieldSource::BodyJson => item.get_string_from_data_json_object(&field_mapping.field),
-                FieldSource::Metadata => item.metadata.get(&field_mapping.field).cloned(),
-            };
-            if let Some(val) = val {
-                log::debug!("[task {}] env override: {}={}", &item.id, key, &val);
-                env.insert(key.to_owned(), val);
+    fn release(&self, key: &str) {
+        let mut busy = self.busy.lock().unwrap();
+        if let Some(count) = busy.get_mut(key) {
+            *count -= 1;
+            if *count == 0 {
+                busy.remove(key);
             }
         }
+    }
+}
 
-        let result = pool.dispatch(&item.data, env).await?;
+impl ResponseHistory {
+    fn get(&self, item_id: &str) -> HashMap<String, String> {
+        let entries = self.entries.lock().unwrap();
+        entries.headers.get(item_id).cloned().unwrap_or_default()
+    }
 
-        if let Some(stderr) = result.stderr_string() {
-            if !stderr.is_empty() {
-                log::warn!("[task {}] {}", &item.id, stderr);
+    fn record(&self, item_id: &str, response: &HttpResponse) {
+        let headers = response.headers().iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.headers.insert(item_id.to_string(), headers).is_none() {
+            entries.order.push_back(item_id.to_string());
+        }
+        while entries.order.len() > MAX_RESPONSE_HISTORY {
+            if let Some(oldest) = entries.order.pop_front() {
+                entries.headers.remove(&oldest);
             }
         }
+    }
 
-        if let Some(stdout) = result.stdout_string() {
-            log::debug!("[task {}] stdout: {}", &item.id, stdout);
+    fn forget(&self, item_id: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.headers.remove(item_id).is_some() {
+            entries.order.retain(|id| id != item_id);
         }
+    }
+}
 
-        let http_response: HttpResponse = result.try_into()?;
-        if !http_response.status().is_success() {
-            return Err(anyhow::anyhow!("script returned status code {}", http_response.status()));
-        }
 
-        if let Ok(body_string) = String::from_utf8(http_response.into_body()) {
-            log::info!("[task {}] task complete: {}", &item.id, body_string);
-        } else {
-            log::info!("[task {}] task complete", &item.id);
+
+//
+// Error handling
+//
+
+/// The ways in which processing a task can fail.
+#[derive(Debug, Error)]
+enum TaskError {
+    #[error(transparent)]
+    Payload(#[from] queue::Error),
+
+    #[error(transparent)]
+    Decoding(#[from] decoding::Error),
+
+    #[error(transparent)]
+    Mapping(#[from] mapping::Error),
+
+    #[error(transparent)]
+    Dispatch(#[from] pool::Error),
+
+    #[error("script returned status code {0}")]
+    Status(StatusCode),
+
+    #[error("concurrency limit reached for key {key}")]
+    ConcurrencyLimit { key: String, retry_delay: i32 },
+
+    #[error("rate limit reached for key {key}")]
+    RateLimit { key: String, retry_delay: i32 },
+
+    #[error("circuit breaker is open")]
+    CircuitOpen,
+}
+
+impl TaskError {
+    /// A short, stable description of the type of failure, suitable for use as a metric label.
+    fn reason(&self) -> &'static str {
+        match self {
+            TaskError::Payload(_) => "payload",
+            TaskError::Decoding(_) => "decoding",
+            TaskError::Mapping(_) => "mapping",
+            TaskError::Dispatch(pool::Error::Io(_)) => "connection",
+            TaskError::Dispatch(pool::Error::FastCgi(_)) => "fastcgi",
+            TaskError::Dispatch(pool::Error::HttpResponse(_)) => "invalid_response",
+            TaskError::Status(_) => "status",
+            TaskError::ConcurrencyLimit { .. } => "concurrency_limit",
+            TaskError::RateLimit { .. } => "rate_limit",
+            TaskError::CircuitOpen => "circuit_open",
         }
+    }
 
-        Ok(item)
-    }.await;
+    /// If the task wasn't attempted, how long (in seconds) to wait before making its item visible
+    /// on the queue again.
+    fn retry_delay(&self) -> Option<i32> {
+        match self {
+            TaskError::ConcurrencyLimit { retry_delay, .. } | TaskError::RateLimit { retry_delay, .. } => Some(*retry_delay),
+            TaskError::CircuitOpen => Some(0),
+            _ => None,
+        }
+    }
 
-    //If the task was successful, remove it from the queue. Otherwise, log the failure.
-    match result.context("task failed") {
-        Ok(item) => {
-            let delete_result = queue.acknowledge(&item).await
-                .context("failed to remove task from queue");
-            if let Err(e) = delete_result {
-                log::error!("[task {}] {:#}", item_id, e);
-            }
-        },
-        Err(e) => {
-            log::error!("[task {}] {:#}", item_id, e);
+    /// The HTTP status code returned by the script, if it returned a valid response.
+    fn status(&self) -> String {
+        match self {
+            TaskError::Status(status) => status.as_str().to_string(),
+            _ => String::new(),
         }
-    };
-}
\ No newline at end of file
+    }
+}
@@ -0,0 +1,102 @@
+use crate::config;
+use crate::health::{Health, Liveness, QueueStatus};
+use crate::metrics;
+use crate::pool::Pool;
+use anyhow::anyhow;
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::Serialize;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::time::timeout;
+
+/// How long the readiness check waits for the FastCGI server to accept a connection.
+const FASTCGI_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+//
+// Data structures
+//
+
+#[derive(Clone)]
+struct AppState {
+    health: Arc<Health>,
+    pool: Arc<Pool>,
+    liveness_timeout: Duration,
+}
+
+#[derive(Serialize)]
+struct ReadinessReport {
+    ready: bool,
+    queue: QueueStatus,
+    fastcgi: FastcgiStatus,
+}
+
+#[derive(Serialize)]
+struct FastcgiStatus {
+    ok: bool,
+    error: Option<String>,
+}
+
+
+//
+// Functions
+//
+
+/// Bind the listener for the HTTP endpoints, so that a failure can be reported at startup.
+pub async fn bind(config: &config::Http) -> io::Result<TcpListener> {
+    let listener = TcpListener::bind((config.address.as_str(), config.port)).await?;
+    log::info!("Serving monitoring endpoints on http://{}", listener.local_addr()?);
+    Ok(listener)
+}
+
+/// Serve the HTTP endpoints used for monitoring fcgiq, until the process exits.
+pub async fn serve(listener: TcpListener, config: config::Http, health: Arc<Health>, pool: Arc<Pool>) -> io::Result<()> {
+    metrics::register();
+    let state = AppState {
+        health,
+        pool,
+        liveness_timeout: Duration::from_secs(config.liveness_timeout),
+    };
+    let app = Router::new()
+        .route("/metrics", get(get_metrics))
+        .route("/healthz", get(get_healthz))
+        .route("/readyz", get(get_readyz))
+        .with_state(state);
+    axum::serve(listener, app).await
+}
+
+async fn get_metrics() -> impl IntoResponse {
+    ([(CONTENT_TYPE, prometheus::TEXT_FORMAT)], metrics::render())
+}
+
+/// Report whether the runner loop is alive.
+async fn get_healthz(State(state): State<AppState>) -> (StatusCode, Json<Liveness>) {
+    let liveness = state.health.liveness(state.liveness_timeout);
+    (status_code(liveness.alive), Json(liveness))
+}
+
+/// Report whether fcgiq is able to receive items from the queue and dispatch them.
+async fn get_readyz(State(state): State<AppState>) -> (StatusCode, Json<ReadinessReport>) {
+    let queue = state.health.queue_status();
+    let fastcgi = match timeout(FASTCGI_CHECK_TIMEOUT, state.pool.check_connection()).await {
+        Ok(Ok(())) => FastcgiStatus { ok: true, error: None },
+        Ok(Err(e)) => FastcgiStatus { ok: false, error: Some(format!("{:#}", anyhow!(e))) },
+        Err(_) => FastcgiStatus { ok: false, error: Some("timed out connecting to FastCGI".to_string()) },
+    };
+    let ready = queue.ok && fastcgi.ok;
+    (status_code(ready), Json(ReadinessReport { ready, queue, fastcgi }))
+}
+
+fn status_code(ok: bool) -> StatusCode {
+    if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
@@ -0,0 +1,173 @@
+use crate::admin::Status;
+use crate::cli::StatsArgs;
+use crate::config::{self, Config};
+use crate::queue::Queue;
+use anyhow::{anyhow, bail, Context, Error};
+use chrono::Utc;
+use std::fmt::Write;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::time::{interval, timeout};
+
+/// How long to wait for the admin API to respond.
+const ADMIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+//
+// Data structures
+//
+
+/// Where to find the admin API of a running instance.
+enum AdminTarget {
+    Socket(String),
+    Tcp(String),
+}
+
+
+//
+// Functions
+//
+
+/// Print the number of messages in the configured queue, and the age of the oldest one. If an
+/// admin API is given, also print the state of the instance serving it. In watch mode, the stats
+/// are refreshed periodically until the process is interrupted.
+pub async fn run(config: Config, args: StatsArgs) -> Result<(), Error> {
+    let admin = match args.admin {
+        None => None,
+        Some(Some(address)) => Some(AdminTarget::parse(address)),
+        Some(None) => {
+            let admin_config = config.admin.as_ref()
+                .ok_or_else(|| anyhow!("No admin API is configured; give its address with --admin <SOCKET|HOST:PORT>"))?;
+            Some(AdminTarget::from_config(admin_config))
+        },
+    };
+    let queue = Queue::from_config(&config.queue).await;
+
+    let Some(watch) = args.watch else {
+        print!("{}", report(&queue, admin.as_ref()).await?);
+        return Ok(());
+    };
+    if watch == 0 {
+        bail!("--watch must be at least 1 second");
+    }
+    let mut refresh = interval(Duration::from_secs(watch));
+    loop {
+        refresh.tick().await;
+        let report = report(&queue, admin.as_ref()).await
+            .unwrap_or_else(|e| format!("Error: {:#}\n", e));
+        //Clear the terminal, and move the cursor to the top left
+        print!("\x1B[2J\x1B[H");
+        println!("{}  (every {}s)\n", Utc::now().format("%Y-%m-%d %H:%M:%S UTC"), watch);
+        print!("{}", report);
+    }
+}
+
+/// Gather the stats, and format them for display.
+async fn report(queue: &Queue, admin: Option<&AdminTarget>) -> Result<String, Error> {
+    let stats = queue.stats().await?;
+    //CloudWatch access is often not granted alongside SQS access, so don't treat it as fatal
+    let oldest_message_age = match queue.oldest_message_age().await {
+        Ok(Some(age)) => format_duration(age),
+        Ok(None) => "unknown (no recent CloudWatch datapoints)".to_string(),
+        Err(e) => format!("unavailable ({:#})", anyhow!(e)),
+    };
+
+    let mut report = String::new();
+    writeln!(report, "Queue {}", queue.name())?;
+    writeln!(report, "  visible          {}", stats.visible)?;
+    writeln!(report, "  not visible      {}", stats.not_visible)?;
+    writeln!(report, "  delayed          {}", stats.delayed)?;
+    writeln!(report, "  oldest message   {}", oldest_message_age)?;
+
+    if let Some(admin) = admin {
+        let status = admin.status().await
+            .with_context(|| format!("Unable to query the admin API at {}", admin))?;
+        let recent = status.recent;
+        let finished = recent.succeeded + recent.failed;
+        writeln!(report, "\nRunner")?;
+        writeln!(report, "  slots busy       {} / {}", status.in_flight.len(), status.max_tasks)?;
+        writeln!(report, "  paused           {}", if status.paused { "yes" } else { "no" })?;
+        writeln!(report, "  draining         {}", if status.draining { "yes" } else { "no" })?;
+        writeln!(
+            report,
+            "  last {:<11} {} succeeded ({:.2}/s), {} failed ({:.2}/s){}",
+            format!("{}s", recent.window_seconds),
+            recent.succeeded, recent.succeeded as f64 / recent.window_seconds as f64,
+            recent.failed, recent.failed as f64 / recent.window_seconds as f64,
+            if finished > 0 {
+                format!(", {:.1}% success", recent.succeeded as f64 * 100.0 / finished as f64)
+            } else {
+                String::new()
+            },
+        )?;
+        if let Some(longest) = status.in_flight.first() {
+            writeln!(report, "  longest task     {} ({})", longest.id, format_duration(Duration::from_secs_f64(longest.elapsed_seconds)))?;
+        }
+    }
+
+    Ok(report)
+}
+
+/// Format a duration as hours, minutes and seconds, e.g. `1h 2m 3s`.
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match (seconds / 3600, seconds / 60 % 60, seconds % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {}s", m, s),
+        (h, m, s) => format!("{}h {}m {}s", h, m, s),
+    }
+}
+
+impl AdminTarget {
+    fn parse(address: String) -> Self {
+        if address.contains('/') {
+            AdminTarget::Socket(address)
+        } else {
+            AdminTarget::Tcp(address)
+        }
+    }
+
+    fn from_config(config: &config::Admin) -> Self {
+        match &config.socket_path {
+            Some(socket_path) => AdminTarget::Socket(socket_path.clone()),
+            None => AdminTarget::Tcp(format!("{}:{}", config.address, config.port.unwrap_or_default())),
+        }
+    }
+
+    /// Retrieve the instance's status from the admin API.
+    async fn status(&self) -> Result<Status, Error> {
+        timeout(ADMIN_TIMEOUT, async {
+            match self {
+                AdminTarget::Socket(path) => get_status(UnixStream::connect(path).await?).await,
+                AdminTarget::Tcp(address) => get_status(TcpStream::connect(address).await?).await,
+            }
+        }).await
+            .map_err(|_| anyhow!("timed out after {}s", ADMIN_TIMEOUT.as_secs()))?
+    }
+}
+
+impl std::fmt::Display for AdminTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AdminTarget::Socket(path) => write!(f, "unix socket {}", path),
+            AdminTarget::Tcp(address) => write!(f, "http://{}", address),
+        }
+    }
+}
+
+/// Make a `GET /status` request over an established connection to the admin API.
+async fn get_status<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S) -> Result<Status, Error> {
+    stream.write_all(b"GET /status HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut parsed = httparse::Response::new(&mut headers);
+    let httparse::Status::Complete(body_start) = parsed.parse(&response)? else {
+        bail!("incomplete response");
+    };
+    if parsed.code != Some(200) {
+        bail!("unexpected status code {}", parsed.code.unwrap_or_default());
+    }
+    serde_json::from_slice(&response[body_start..]).context("invalid status response")
+}
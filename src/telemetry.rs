@@ -0,0 +1,200 @@
+use crate::config;
+use opentelemetry::propagation::{Extractor, TextMapPropagator};
+use opentelemetry::trace::{Status, TraceContextExt, Tracer};
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_otlp::{ExporterBuildError, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use std::collections::HashMap;
+use std::fmt::Display;
+
+/// The W3C trace context header, which may also be supplied as a message attribute.
+const TRACEPARENT: &str = "traceparent";
+
+/// The message system attribute through which AWS X-Ray propagates trace context.
+const AWS_TRACE_HEADER: &str = "AWSTraceHeader";
+
+//
+// Data structures
+//
+
+/// Exposes an item's metadata to the trace context propagator, matching keys case-insensitively.
+struct MetadataExtractor<'a>(&'a HashMap<String, String>);
+
+
+//
+// Functions
+//
+
+/// Configure trace context propagation, and (if configured) the export of spans to an OTLP
+/// collector. Returns the tracer provider, which must be shut down before exit to flush any
+/// pending spans.
+pub fn init(config: Option<&config::Tracing>) -> Result<Option<SdkTracerProvider>, ExporterBuildError> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let Some(config) = config else {
+        return Ok(None);
+    };
+    let mut exporter = opentelemetry_otlp::SpanExporter::builder().with_http();
+    if !config.otlp_endpoint.is_empty() {
+        exporter = exporter.with_endpoint(&config.otlp_endpoint);
+    }
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter.build()?)
+        .with_resource(Resource::builder().with_service_name(config.service_name.clone()).build())
+        .build();
+    global::set_tracer_provider(provider.clone());
+    Ok(Some(provider))
+}
+
+/// Flush any pending spans and stop exporting.
+pub fn shutdown(provider: Option<SdkTracerProvider>) {
+    if let Some(provider) = provider {
+        if let Err(e) = provider.shutdown() {
+            log::warn!("Unable to flush traces: {}", e);
+        }
+    }
+}
+
+/// Determine the trace context of the producer of a queue item, from its `traceparent` message
+/// attribute or, failing that, its `AWSTraceHeader` system attribute.
+pub fn extract_context(metadata: &HashMap<String, String>) -> Context {
+    let extractor = MetadataExtractor(metadata);
+    if extractor.get(TRACEPARENT).is_some() {
+        return global::get_text_map_propagator(|propagator| propagator.extract(&extractor));
+    }
+    if let Some(traceparent) = extractor.get(AWS_TRACE_HEADER).and_then(xray_to_traceparent) {
+        let carrier = HashMap::from([(TRACEPARENT.to_string(), traceparent)]);
+        return TraceContextPropagator::new().extract(&carrier);
+    }
+    Context::new()
+}
+
+/// Start a span as a child of the given context, returning a new context containing the span.
+pub fn start_span(name: &'static str, parent: &Context, attributes: Vec<KeyValue>) -> Context {
+    let tracer = global::tracer("fcgiq");
+    let span = tracer.span_builder(name)
+        .with_attributes(attributes)
+        .start_with_context(&tracer, parent);
+    parent.with_span(span)
+}
+
+/// End the span in the given context, marking it as successful or failed according to the result
+/// of the operation it represents.
+pub fn end_span<T, E: Display>(cx: &Context, result: &Result<T, E>) {
+    let span = cx.span();
+    match result {
+        Ok(_) => span.set_status(Status::Ok),
+        Err(e) => span.set_status(Status::error(e.to_string())),
+    }
+    span.end();
+}
+
+/// Encode the span context as a W3C `traceparent` value.
+pub fn traceparent(cx: &Context) -> Option<String> {
+    let mut carrier = HashMap::new();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(cx, &mut carrier));
+    carrier.remove(TRACEPARENT)
+}
+
+/// Convert an AWS X-Ray trace header (e.g.
+/// `Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1`) into a W3C
+/// `traceparent` value.
+fn xray_to_traceparent(header: &str) -> Option<String> {
+    let mut root = None;
+    let mut parent = None;
+    let mut sampled = "00";
+    for field in header.split(';') {
+        match field.trim().split_once('=') {
+            Some(("Root", value)) => root = Some(value),
+            Some(("Parent", value)) => parent = Some(value),
+            Some(("Sampled", "1")) => sampled = "01",
+            _ => {},
+        }
+    }
+    //The root is the version, then the time (8 hex digits) and a random part (24 hex digits)
+    let (time, random) = root?.strip_prefix("1-")?.split_once('-')?;
+    let parent = parent?;
+    if !is_hex(time, 8) || !is_hex(random, 24) || !is_hex(parent, 16) {
+        return None;
+    }
+    Some(format!("00-{}{}-{}-{}", time, random, parent, sampled))
+}
+
+/// Whether a string is the given number of hex digits, which aren't all zero (an invalid ID in a
+/// `traceparent`).
+fn is_hex(str: &str, len: usize) -> bool {
+    str.len() == len && str.chars().all(|c| c.is_ascii_hexdigit()) && str.chars().any(|c| c != '0')
+}
+
+impl Extractor for MetadataExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_xray_header() {
+        assert_eq!(
+            xray_to_traceparent("Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1").as_deref(),
+            Some("00-5759e988bd862e3fe1be46a994272793-53995c3f42cd8ad8-01")
+        );
+    }
+
+    #[test]
+    fn converts_fields_in_any_order_with_spaces() {
+        assert_eq!(
+            xray_to_traceparent("Sampled=1; Parent=53995c3f42cd8ad8; Root=1-5759e988-bd862e3fe1be46a994272793").as_deref(),
+            Some("00-5759e988bd862e3fe1be46a994272793-53995c3f42cd8ad8-01")
+        );
+    }
+
+    #[test]
+    fn unsampled_unless_sampled_is_1() {
+        let root = "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8";
+        let expected = Some("00-5759e988bd862e3fe1be46a994272793-53995c3f42cd8ad8-00");
+        assert_eq!(xray_to_traceparent(&format!("{};Sampled=0", root)).as_deref(), expected);
+        assert_eq!(xray_to_traceparent(&format!("{};Sampled=?", root)).as_deref(), expected);
+        assert_eq!(xray_to_traceparent(root).as_deref(), expected);
+    }
+
+    #[test]
+    fn requires_parent() {
+        assert_eq!(xray_to_traceparent("Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=1"), None);
+    }
+
+    #[test]
+    fn rejects_malformed_header() {
+        for header in [
+            "",
+            "garbage",
+            "Parent=53995c3f42cd8ad8;Sampled=1",
+            //Wrong version
+            "Root=2-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8",
+            //Missing the separator between the time and the random part
+            "Root=1-5759e988bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8",
+            //Parts of the wrong length
+            "Root=1-5759e98-8bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8",
+            "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8a",
+            //Not hexadecimal
+            "Root=1-5759e988-bd862e3fe1be46a99427279z;Parent=53995c3f42cd8ad8",
+            "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8adg",
+            //All zero
+            "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=0000000000000000",
+        ] {
+            assert_eq!(xray_to_traceparent(header), None, "{}", header);
+        }
+    }
+}